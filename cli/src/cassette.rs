@@ -0,0 +1,384 @@
+use reqwest::{header::HeaderMap, Method, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+	collections::BTreeMap,
+	fs,
+	sync::{Arc, Mutex},
+};
+
+use wasmhaiku_glue::error::Error;
+
+use crate::initial::Args;
+use crate::inject::redact;
+
+// Bodies are kept readable when they are text
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Body {
+	Text(String),
+	Bytes(Vec<u8>),
+}
+
+impl From<&[u8]> for Body {
+	fn from(raw: &[u8]) -> Body {
+		match String::from_utf8(raw.to_vec()) {
+			Ok(s) => Body::Text(s),
+			Err(e) => Body::Bytes(e.into_bytes()),
+		}
+	}
+}
+
+impl Body {
+	fn into_bytes(self) -> Vec<u8> {
+		match self {
+			Body::Text(s) => s.into_bytes(),
+			Body::Bytes(b) => b,
+		}
+	}
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RecordedRequest {
+	pub method: String,
+	pub url: String,
+	pub headers: BTreeMap<String, String>,
+	pub body: Body,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub fileparts: Option<Body>,
+}
+
+impl RecordedRequest {
+	pub fn new(
+		method: &Method,
+		url: &str,
+		headers: &HeaderMap,
+		body: &[u8],
+		fileparts: Option<&[u8]>,
+	) -> RecordedRequest {
+		let mut h: BTreeMap<String, String> = BTreeMap::new();
+		for (k, v) in headers.iter() {
+			let v = redact(&String::from_utf8_lossy(v.as_bytes()));
+			h.entry(k.as_str().to_string())
+				.and_modify(|e| {
+					e.push_str(", ");
+					e.push_str(&v);
+				})
+				.or_insert(v);
+		}
+		RecordedRequest {
+			method: method.to_string(),
+			url: redact(url),
+			headers: h,
+			body: match Body::from(body) {
+				Body::Text(s) => Body::Text(redact(&s)),
+				b => b,
+			},
+			fileparts: fileparts.map(|f| f.into()),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct RecordedResponse {
+	status: u16,
+	headers: Value,
+	body: Body,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Interaction {
+	request: RecordedRequest,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	response: Option<RecordedResponse>,
+	// {"code", "message"} of a failed request
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	error: Option<Value>,
+}
+
+#[derive(Debug, PartialEq)]
+enum MatchKey {
+	Method,
+	Url,
+	Path,
+	Query,
+	Body,
+	Header(String),
+}
+
+impl MatchKey {
+	fn parse(raw: &str) -> Result<MatchKey, String> {
+		match raw.trim() {
+			"method" => Ok(MatchKey::Method),
+			"url" => Ok(MatchKey::Url),
+			"path" => Ok(MatchKey::Path),
+			"query" => Ok(MatchKey::Query),
+			"body" => Ok(MatchKey::Body),
+			k => match k.strip_prefix("header:") {
+				Some(name) => Ok(MatchKey::Header(name.to_ascii_lowercase())),
+				None => Err(format!(
+					"Unknown match key {}, expected method, url, path, query, body or header:<name>",
+					k
+				)),
+			},
+		}
+	}
+
+	fn matches(&self, a: &RecordedRequest, b: &RecordedRequest) -> bool {
+		let url = |r: &RecordedRequest| Url::parse(&r.url).ok();
+		match self {
+			MatchKey::Method => a.method == b.method,
+			MatchKey::Url => a.url == b.url,
+			MatchKey::Path => {
+				url(a).map(|u| u.path().to_string()) == url(b).map(|u| u.path().to_string())
+			}
+			MatchKey::Query => {
+				let query = |r: &RecordedRequest| {
+					url(r).map(|u| {
+						let mut pairs: Vec<(String, String)> =
+							u.query_pairs().into_owned().collect();
+						pairs.sort();
+						pairs
+					})
+				};
+				query(a) == query(b)
+			}
+			MatchKey::Body => a.body == b.body && a.fileparts == b.fileparts,
+			MatchKey::Header(name) => a.headers.get(name) == b.headers.get(name),
+		}
+	}
+}
+
+enum Mode {
+	Record,
+	Replay,
+}
+
+struct State {
+	interactions: Vec<Interaction>,
+	// replayed interactions, so repeated requests get the responses in recorded order
+	used: Vec<bool>,
+}
+
+// Outbound exchanges written to or served from a JSON file
+pub struct Cassette {
+	path: String,
+	mode: Mode,
+	match_on: Vec<MatchKey>,
+	state: Mutex<State>,
+}
+
+impl std::fmt::Debug for Cassette {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		write!(f, "Cassette({})", self.path)
+	}
+}
+
+impl Cassette {
+	// Shared by every reload, so a recording covers the whole run
+	pub fn from_args(args: &Args) -> Result<Option<Arc<Cassette>>, String> {
+		let match_on = args
+			.match_on
+			.iter()
+			.map(|k| MatchKey::parse(k))
+			.collect::<Result<Vec<MatchKey>, String>>()?;
+
+		let (path, mode, interactions) = match (&args.record, &args.replay) {
+			(Some(path), _) => (path.clone(), Mode::Record, vec![]),
+			(None, Some(path)) => {
+				let raw = match fs::read_to_string(path) {
+					Ok(s) => s,
+					Err(e) => return Err(format!("Failed to read {}. {}", path, e)),
+				};
+				let interactions: Vec<Interaction> = match serde_json::from_str(&raw) {
+					Ok(i) => i,
+					Err(e) => return Err(format!("Invalid cassette {}. {}", path, e)),
+				};
+				(path.clone(), Mode::Replay, interactions)
+			}
+			(None, None) => return Ok(None),
+		};
+
+		Ok(Some(Arc::new(Cassette {
+			path,
+			mode,
+			match_on,
+			state: Mutex::new(State {
+				used: vec![false; interactions.len()],
+				interactions,
+			}),
+		})))
+	}
+
+	pub fn is_replay(&self) -> bool {
+		matches!(self.mode, Mode::Replay)
+	}
+
+	pub fn replay(&self, req: &RecordedRequest) -> Result<(u16, String, Vec<u8>), Error> {
+		let mut state = self.state.lock().unwrap();
+		let matching: Vec<usize> = state
+			.interactions
+			.iter()
+			.enumerate()
+			.filter(|(_, i)| self.match_on.iter().all(|k| k.matches(&i.request, req)))
+			.map(|(index, _)| index)
+			.collect();
+		// the last match is served again once all of them were used
+		let index = match matching
+			.iter()
+			.find(|i| !state.used[**i])
+			.or(matching.last())
+		{
+			Some(i) => *i,
+			None => {
+				let message = format!(
+					"No interaction in the cassette {} matches {} {}",
+					self.path, req.method, req.url
+				);
+				eprintln!("{}", message);
+				return Err(Error::Other(message));
+			}
+		};
+		state.used[index] = true;
+
+		let interaction = state.interactions[index].clone();
+		match (interaction.response, interaction.error) {
+			(Some(r), _) => Ok((r.status, r.headers.to_string(), r.body.into_bytes())),
+			(None, Some(e)) => Err(e.to_string().as_bytes().into()),
+			(None, None) => Err(Error::Other(format!(
+				"The interaction {} of the cassette {} has no response",
+				index, self.path
+			))),
+		}
+	}
+
+	// The whole cassette is rewritten, so it is complete whenever the run stops
+	pub fn record(&self, req: RecordedRequest, ret: &Result<(u16, String, Vec<u8>), Error>) {
+		let (response, error) = match ret {
+			Ok((status, headers, body)) => (
+				Some(RecordedResponse {
+					status: *status,
+					headers: serde_json::from_str(headers).unwrap_or_default(),
+					body: body.as_slice().into(),
+				}),
+				None,
+			),
+			Err(e) => (None, serde_json::from_slice(&e.to_vec()).ok()),
+		};
+
+		let mut state = self.state.lock().unwrap();
+		state.interactions.push(Interaction {
+			request: req,
+			response,
+			error,
+		});
+		state.used.push(false);
+		let raw = serde_json::to_string_pretty(&state.interactions).unwrap();
+		if let Err(e) = fs::write(&self.path, raw) {
+			eprintln!("Failed to write the cassette {}. {}", self.path, e);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn request(method: &str, url: &str, body: &str) -> RecordedRequest {
+		RecordedRequest {
+			method: method.to_string(),
+			url: url.to_string(),
+			headers: BTreeMap::from([(String::from("x-tenant"), String::from("a"))]),
+			body: Body::Text(body.to_string()),
+			fileparts: None,
+		}
+	}
+
+	fn cassette(match_on: &[&str], interactions: Vec<(RecordedRequest, &str)>) -> Cassette {
+		let interactions: Vec<Interaction> = interactions
+			.into_iter()
+			.map(|(request, body)| Interaction {
+				request,
+				response: Some(RecordedResponse {
+					status: 200,
+					headers: Value::Null,
+					body: Body::Text(body.to_string()),
+				}),
+				error: None,
+			})
+			.collect();
+		Cassette {
+			path: String::from("test.json"),
+			mode: Mode::Replay,
+			match_on: match_on
+				.iter()
+				.map(|k| MatchKey::parse(k).unwrap())
+				.collect(),
+			state: Mutex::new(State {
+				used: vec![false; interactions.len()],
+				interactions,
+			}),
+		}
+	}
+
+	fn replayed(cassette: &Cassette, req: &RecordedRequest) -> Option<String> {
+		cassette
+			.replay(req)
+			.ok()
+			.map(|(_, _, body)| String::from_utf8(body).unwrap())
+	}
+
+	#[test]
+	fn match_keys() {
+		assert_eq!(MatchKey::parse("method"), Ok(MatchKey::Method));
+		assert_eq!(MatchKey::parse(" url "), Ok(MatchKey::Url));
+		assert_eq!(MatchKey::parse("path"), Ok(MatchKey::Path));
+		assert_eq!(MatchKey::parse("query"), Ok(MatchKey::Query));
+		assert_eq!(MatchKey::parse("body"), Ok(MatchKey::Body));
+		assert_eq!(
+			MatchKey::parse("header:X-Tenant"),
+			Ok(MatchKey::Header(String::from("x-tenant")))
+		);
+		assert!(MatchKey::parse("host").is_err());
+		assert!(MatchKey::parse("").is_err());
+	}
+
+	#[test]
+	fn query_order() {
+		let c = cassette(
+			&["method", "path", "query"],
+			vec![(request("GET", "https://example.com/x?a=1&b=2", ""), "ab")],
+		);
+		let same = request("GET", "https://example.com/x?b=2&a=1", "");
+		assert_eq!(replayed(&c, &same).as_deref(), Some("ab"));
+		let other = request("GET", "https://example.com/x?a=1&b=3", "");
+		assert_eq!(replayed(&c, &other), None);
+		let post = request("POST", "https://example.com/x?a=1&b=2", "");
+		assert_eq!(replayed(&c, &post), None);
+
+		// the url key compares the raw url
+		let c = cassette(
+			&["url"],
+			vec![(request("GET", "https://example.com/x?a=1&b=2", ""), "ab")],
+		);
+		assert_eq!(replayed(&c, &same), None);
+	}
+
+	#[test]
+	fn sequencing() {
+		let get = request("GET", "https://example.com/x", "");
+		let c = cassette(
+			&["method", "url"],
+			vec![
+				(get.clone(), "first"),
+				(request("GET", "https://example.com/y", ""), "other"),
+				(get.clone(), "second"),
+			],
+		);
+		assert_eq!(replayed(&c, &get).as_deref(), Some("first"));
+		assert_eq!(replayed(&c, &get).as_deref(), Some("second"));
+		// the last match repeats once all of them were used
+		assert_eq!(replayed(&c, &get).as_deref(), Some("second"));
+		assert_eq!(replayed(&c, &get).as_deref(), Some("second"));
+	}
+}
@@ -0,0 +1,344 @@
+use reqwest::Url;
+use std::{
+	collections::HashMap,
+	net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+	sync::Arc,
+};
+use tokio::net::lookup_host;
+
+use wasmhaiku_glue::error::Error;
+
+use crate::route_config::{Config, EgressConfig};
+
+// Egress policies of a connector, looked up by the function sending the request
+#[derive(Debug, Default)]
+pub struct Egress {
+	default: Arc<Policy>,
+	funcs: HashMap<String, Arc<Policy>>,
+}
+
+// The clients following a policy's redirects are cached under its name
+#[derive(Debug, Default)]
+pub struct Policy {
+	pub name: String,
+	config: EgressConfig,
+}
+
+fn host_matches(pattern: &str, host: &str) -> bool {
+	match pattern.strip_prefix("*.") {
+		Some(domain) => host
+			.to_ascii_lowercase()
+			.strip_suffix(&domain.to_ascii_lowercase())
+			.map(|sub| sub.len() > 1 && sub.ends_with('.'))
+			.unwrap_or(false),
+		None => pattern.eq_ignore_ascii_case(host),
+	}
+}
+
+fn is_private_v4(ip: &Ipv4Addr) -> bool {
+	let octets = ip.octets();
+	ip.is_loopback()
+		|| ip.is_private()
+		|| ip.is_link_local()
+		|| ip.is_documentation()
+		|| ip.is_multicast()
+		// "this network", 0.0.0.0/8
+		|| octets[0] == 0
+		// shared address space of carrier-grade NAT
+		|| (octets[0] == 100 && (octets[1] & 0xc0) == 64)
+		// IETF protocol assignments
+		|| (octets[0] == 192 && octets[1] == 0 && octets[2] == 0)
+		// benchmarking
+		|| (octets[0] == 198 && (octets[1] & 0xfe) == 18)
+		// reserved, including the broadcast address
+		|| octets[0] >= 240
+}
+
+fn is_private_v6(ip: &Ipv6Addr) -> bool {
+	let segments = ip.segments();
+	if let Some(v4) = ip.to_ipv4_mapped() {
+		return is_private_v4(&v4);
+	}
+	// NAT64 embeds the IPv4 address in the last 32 bits
+	if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+		let [a, b] = segments[6].to_be_bytes();
+		let [c, d] = segments[7].to_be_bytes();
+		return is_private_v4(&Ipv4Addr::new(a, b, c, d));
+	}
+	ip.is_loopback()
+		|| ip.is_unspecified()
+		|| ip.is_multicast()
+		// unique local and link-local ranges
+		|| (segments[0] & 0xfe00) == 0xfc00
+		|| (segments[0] & 0xffc0) == 0xfe80
+}
+
+// IPv6 hosts come in brackets
+fn literal_ip(url: &Url) -> Option<IpAddr> {
+	url.host_str()?
+		.trim_start_matches('[')
+		.trim_end_matches(']')
+		.parse()
+		.ok()
+}
+
+fn is_private(ip: &IpAddr) -> bool {
+	match ip {
+		IpAddr::V4(ip) => is_private_v4(ip),
+		IpAddr::V6(ip) => is_private_v6(ip),
+	}
+}
+
+impl Egress {
+	pub fn new(config: &Config) -> Egress {
+		let mut funcs = HashMap::new();
+		for route in config.route.iter() {
+			if let Some(egress) = &route.egress {
+				let policy = Arc::new(Policy {
+					name: route.func_name.clone(),
+					config: egress.clone(),
+				});
+				funcs.insert(route.func_name.clone(), policy.clone());
+				if let Some(async_func_name) = &route.async_func_name {
+					funcs.insert(async_func_name.clone(), policy);
+				}
+			}
+		}
+		Egress {
+			default: Arc::new(Policy {
+				name: String::new(),
+				config: config.egress.clone(),
+			}),
+			funcs,
+		}
+	}
+
+	pub fn policy(&self, func_name: &str) -> Arc<Policy> {
+		self.funcs.get(func_name).unwrap_or(&self.default).clone()
+	}
+}
+
+impl Policy {
+	// Refuse a destination the policy doesn't allow, without any lookup so it
+	// can be run again on every redirect
+	pub fn check_url(&self, url: &Url) -> Result<(), Error> {
+		if !self
+			.config
+			.allow_schemes
+			.iter()
+			.any(|s| s.eq_ignore_ascii_case(url.scheme()))
+		{
+			return Err(Error::Forbidden(format!(
+				"Scheme {} is not allowed by the egress policy",
+				url.scheme()
+			)));
+		}
+
+		let host = url.host_str().unwrap_or_default();
+		if !self.config.allow_hosts.is_empty()
+			&& !self
+				.config
+				.allow_hosts
+				.iter()
+				.any(|p| host_matches(p, host))
+		{
+			return Err(Error::Forbidden(format!(
+				"Host {} is not allowed by the egress policy",
+				host
+			)));
+		}
+
+		let port = url.port_or_known_default().unwrap_or_default();
+		if !self.config.allow_ports.is_empty() && !self.config.allow_ports.contains(&port) {
+			return Err(Error::Forbidden(format!(
+				"Port {} is not allowed by the egress policy",
+				port
+			)));
+		}
+
+		if self.config.block_private {
+			if let Some(ip) = literal_ip(url).filter(is_private) {
+				return Err(Error::Forbidden(format!(
+					"Host {} is the non-public address {}",
+					host, ip
+				)));
+			}
+		}
+
+		Ok(())
+	}
+
+	// Whether the addresses of the host have to be looked up to be checked
+	pub fn needs_lookup(&self, url: &Url) -> bool {
+		self.config.block_private && url.host_str().is_some() && literal_ip(url).is_none()
+	}
+
+	// The checked address to connect to, so the host can't resolve elsewhere
+	// by the time the connection is made
+	pub async fn resolve(&self, url: &Url) -> Result<Option<SocketAddr>, Error> {
+		if !self.needs_lookup(url) {
+			return Ok(None);
+		}
+		let host = url.host_str().unwrap_or_default();
+		let port = url.port_or_known_default().unwrap_or_default();
+		let addrs: Vec<SocketAddr> = match lookup_host((host, port)).await {
+			Ok(addrs) => addrs.collect(),
+			Err(e) => return Err(Error::Dns(format!("{:?}", e))),
+		};
+		if let Some(addr) = addrs.iter().find(|a| is_private(&a.ip())) {
+			return Err(Error::Forbidden(format!(
+				"Host {} resolves to the non-public address {}",
+				host,
+				addr.ip()
+			)));
+		}
+		match addrs.first() {
+			Some(addr) => Ok(Some(*addr)),
+			None => Err(Error::Dns(format!("No address found for {}", host))),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn policy(raw: &str) -> Policy {
+		Policy {
+			name: String::new(),
+			config: toml::from_str(raw).unwrap(),
+		}
+	}
+
+	fn url(raw: &str) -> Url {
+		Url::parse(raw).unwrap()
+	}
+
+	#[test]
+	fn hosts() {
+		assert!(host_matches("*.example.com", "api.example.com"));
+		assert!(host_matches("*.example.com", "a.b.Example.com"));
+		assert!(!host_matches("*.example.com", "example.com"));
+		assert!(!host_matches("*.example.com", "evilexample.com"));
+		assert!(host_matches("example.com", "EXAMPLE.com"));
+		assert!(!host_matches("example.com", "api.example.com"));
+	}
+
+	#[test]
+	fn private_ranges() {
+		let private = [
+			"127.0.0.1",
+			"10.1.2.3",
+			"172.16.0.1",
+			"192.168.1.1",
+			"169.254.169.254",
+			"0.0.0.0",
+			"0.1.2.3",
+			"100.64.0.1",
+			"192.0.0.8",
+			"198.18.0.1",
+			"198.19.255.255",
+			"224.0.0.1",
+			"240.0.0.1",
+			"255.255.255.255",
+			"::1",
+			"::",
+			"fc00::1",
+			"fe80::1",
+			"ff02::1",
+			"::ffff:127.0.0.1",
+			"::ffff:10.0.0.1",
+			"64:ff9b::a9fe:a9fe",
+			"64:ff9b::7f00:1",
+		];
+		for ip in private.iter() {
+			assert!(is_private(&ip.parse().unwrap()), "{} is private", ip);
+		}
+		let public = [
+			"1.1.1.1",
+			"100.128.0.1",
+			"192.0.1.1",
+			"198.20.0.1",
+			"2606:4700::1111",
+			"::ffff:1.1.1.1",
+			"64:ff9b::101:101",
+		];
+		for ip in public.iter() {
+			assert!(!is_private(&ip.parse().unwrap()), "{} is public", ip);
+		}
+	}
+
+	#[test]
+	fn check_url() {
+		let p = policy(
+			r#"
+			allow_hosts = ["*.example.com", "example.com"]
+			allow_schemes = ["https"]
+			allow_ports = [443, 8443]
+			"#,
+		);
+		assert!(p.check_url(&url("https://api.example.com/x")).is_ok());
+		assert!(p.check_url(&url("https://example.com:8443/x")).is_ok());
+		assert!(p.check_url(&url("https://evilexample.com/")).is_err());
+		assert!(p.check_url(&url("http://example.com/")).is_err());
+		assert!(p.check_url(&url("https://example.com:8080/")).is_err());
+
+		let p = policy("block_private = true");
+		let forbidden = |raw: &str| matches!(p.check_url(&url(raw)), Err(Error::Forbidden(_)));
+		assert!(forbidden("http://127.0.0.1/"));
+		assert!(forbidden("http://[::1]:8080/"));
+		assert!(forbidden("http://[::ffff:169.254.169.254]/"));
+		assert!(forbidden("http://[64:ff9b::a9fe:a9fe]/"));
+		assert!(forbidden("http://0.0.0.0/"));
+		assert!(!forbidden("http://1.1.1.1/"));
+		assert!(!forbidden("http://[2606:4700::1111]/"));
+		// names are checked once resolved
+		assert!(!forbidden("http://localhost/"));
+		assert!(p.needs_lookup(&url("http://localhost/")));
+		assert!(!p.needs_lookup(&url("http://1.1.1.1/")));
+	}
+
+	#[tokio::test]
+	async fn resolve() {
+		let p = policy("block_private = true");
+		assert!(matches!(
+			p.resolve(&url("http://localhost:8080/")).await,
+			Err(Error::Forbidden(_))
+		));
+		assert_eq!(p.resolve(&url("http://1.1.1.1/")).await, Ok(None));
+
+		let p = policy("");
+		assert_eq!(p.resolve(&url("http://localhost/")).await, Ok(None));
+	}
+
+	#[test]
+	fn policies() {
+		let config = Config::parse(
+			r#"
+			[egress]
+			allow_hosts = ["default.example"]
+
+			[[route]]
+			func_name = "f"
+			async_func_name = "f_async"
+			path = "/f"
+			method = "GET"
+			[route.egress]
+			allow_hosts = ["f.example"]
+			"#,
+		)
+		.unwrap();
+		let egress = Egress::new(&config);
+		assert_eq!(egress.policy("f").name, "f");
+		assert_eq!(egress.policy("f_async").name, "f");
+		assert_eq!(egress.policy("g").name, "");
+		assert!(egress
+			.policy("f_async")
+			.check_url(&url("http://f.example/"))
+			.is_ok());
+		assert!(egress
+			.policy("g")
+			.check_url(&url("http://f.example/"))
+			.is_err());
+	}
+}
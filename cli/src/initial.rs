@@ -1,31 +1,162 @@
+use crate::cassette::Cassette;
+use crate::egress::Egress;
+use crate::inject::Injected;
+use crate::invoke::InvokeArgs;
+use crate::jobs::JobQueue;
+use crate::kv::Kv;
+use crate::mocks::Mocks;
+use crate::outbound::Outbound;
+use crate::pool::Pool;
 use crate::route_config::Config;
-use crate::wasm::Wasm;
-use clap::Parser;
+use crate::validate;
+use clap::{Parser, Subcommand};
+use std::{
+	net::IpAddr,
+	sync::{Arc, RwLock},
+};
 
 /// Load and run a Wasm as a Haiku Connector
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
-struct Args {
+pub struct Args {
 	/// Path of the route config
 	#[clap(short, long, value_parser)]
-	config: String,
+	pub config: String,
 
 	/// Path of the Wasm file
 	#[clap(short, long, value_parser)]
-	wasm: String,
+	pub wasm: String,
+
+	/// Address to listen on
+	#[clap(long, value_parser, default_value = "127.0.0.1")]
+	pub host: IpAddr,
+
+	/// Port to listen on
+	#[clap(short, long, value_parser, env = "PORT", default_value_t = 9000)]
+	pub port: u16,
+
+	/// Path of the PEM encoded TLS certificate chain, reloaded on SIGHUP
+	#[clap(long, value_parser, requires = "tls-key")]
+	pub tls_cert: Option<String>,
+
+	/// Path of the PEM encoded TLS private key, reloaded on SIGHUP
+	#[clap(long, value_parser, requires = "tls-cert")]
+	pub tls_key: Option<String>,
+
+	/// Seconds to wait for in-flight requests and background work on shutdown
+	#[clap(long, value_parser, default_value_t = 30)]
+	pub shutdown_timeout: u64,
+
+	/// Bearer token enabling `POST /_haiku/reload`, `GET /_haiku/jobs/:id` and
+	/// `GET /_haiku/dead-letters`, which aren't served without one. The instances replaced by a reload aren't freed, see `--watch`
+	#[clap(long, value_parser, env = "HAIKU_ADMIN_TOKEN", hide_env_values = true)]
+	pub admin_token: Option<String>,
+
+	/// Reload the Wasm file and the route config when either changes. The host
+	/// functions of replaced instances are never freed, so the instances created
+	/// by the process are capped: a reload fails once MAX_HOST_FUNC_LENGTH
+	/// (65536 by default, 18 per instance) is used up and the process must restart
+	#[clap(long, value_parser)]
+	pub watch: bool,
+
+	/// Write every outbound exchange to this cassette file
+	#[clap(long, value_parser, conflicts_with = "replay")]
+	pub record: Option<String>,
+
+	/// Serve outbound requests from this cassette file instead of the network
+	#[clap(long, value_parser)]
+	pub replay: Option<String>,
+
+	/// Keys matching a replayed request: method, url, path, query, body or header:<name>
+	#[clap(
+		long,
+		value_parser,
+		value_delimiter = ',',
+		default_value = "method,url"
+	)]
+	pub match_on: Vec<String>,
+
+	/// Path of a TOML file of mocked upstream responses, reloaded with the route config
+	#[clap(long, value_parser)]
+	pub mocks: Option<String>,
+
+	/// Directory preopened read-write for the guest as `guest:host` or a single path, may be repeated
+	#[clap(long = "dir", value_parser)]
+	pub dirs: Vec<String>,
+
+	/// Program arg of the guest, appended to the args of the route config, may be repeated
+	#[clap(long = "arg", value_parser, allow_hyphen_values = true)]
+	pub wasi_args: Vec<String>,
+
+	#[clap(subcommand)]
+	pub command: Option<Command>,
 }
 
-pub struct Initial {
-	pub wasm: Wasm,
+#[derive(Subcommand, Debug)]
+pub enum Command {
+	/// Check the route config against the exports of the Wasm file
+	Validate,
+	/// Call a function of the Wasm file without starting the server
+	Invoke(InvokeArgs),
+}
+
+pub struct Connector {
+	pub pool: Arc<Pool>,
 	pub config: Config,
 }
 
+impl Connector {
+	pub fn load(args: &Args, cassette: Option<Arc<Cassette>>) -> Result<Connector, String> {
+		let config = Config::new(args.config.clone())?;
+		validate::check_exports(&config, &args.wasm)?;
+		let mocks = Mocks::new(&args.config, &config, args.mocks.as_ref())?;
+		let outbound = Outbound::new(
+			None,
+			Egress::new(&config),
+			mocks,
+			cassette,
+			&config.outbound,
+		)?;
+		let kv = Kv::new(&config.kv, &args.wasm)?;
+		let injected = Injected::new(&config.inject, &config.wasi, args)?;
+		let pool = Pool::new(
+			args.wasm.clone(),
+			config.pool.clone(),
+			outbound,
+			kv,
+			injected,
+		);
+		pool.init()?;
+		Ok(Connector { pool, config })
+	}
+}
+
+pub struct Initial {
+	pub args: Args,
+	pub jobs: JobQueue,
+	pub cassette: Option<Arc<Cassette>>,
+	connector: RwLock<Arc<Connector>>,
+}
+
 impl Initial {
 	pub fn new() -> Initial {
 		let args = Args::parse();
+		let cassette = Cassette::from_args(&args).unwrap();
+		let connector = Connector::load(&args, cassette.clone()).unwrap();
 		Initial {
-			wasm: Wasm::new(args.wasm),
-			config: Config::new(args.config),
+			jobs: JobQueue::new(connector.config.queue.clone()),
+			cassette,
+			connector: RwLock::new(Arc::new(connector)),
+			args,
 		}
 	}
+
+	pub fn connector(&self) -> Arc<Connector> {
+		self.connector.read().unwrap().clone()
+	}
+
+	// Requests already running keep the previous connector until they finish
+	pub fn swap(&self, connector: Arc<Connector>) {
+		*self.connector.write().unwrap() = connector;
+	}
 }
@@ -0,0 +1,228 @@
+use lazy_static::lazy_static;
+use std::{collections::HashMap, env, fs, path::Path, sync::RwLock};
+
+use crate::initial::Args;
+use crate::route_config::{InjectConfig, PreopenConfig, VarConfig, VarSource, WasiConfig};
+
+const REDACTED: &str = "[REDACTED]";
+
+lazy_static! {
+	// Values of every secret loaded so far, kept after a reload drops them
+	static ref SECRETS: RwLock<Vec<String>> = RwLock::new(vec![]);
+}
+
+// Hide the secret values in a message before it is logged or recorded
+pub fn redact(message: &str) -> String {
+	let secrets = SECRETS.read().unwrap();
+	secrets
+		.iter()
+		.fold(message.to_string(), |m, s| m.replace(s.as_str(), REDACTED))
+}
+
+fn parse_dotenv(raw: &str) -> HashMap<String, String> {
+	let mut vars = HashMap::new();
+	for line in raw.lines() {
+		let line = line.trim();
+		if line.is_empty() || line.starts_with('#') {
+			continue;
+		}
+		let line = line.strip_prefix("export ").unwrap_or(line);
+		if let Some((k, v)) = line.split_once('=') {
+			let v = v.trim();
+			let v = match (v.chars().next(), v.chars().last()) {
+				(Some('"'), Some('"')) | (Some('\''), Some('\'')) if v.len() > 1 => {
+					&v[1..v.len() - 1]
+				}
+				_ => v,
+			};
+			vars.insert(k.trim().to_string(), v.to_string());
+		}
+	}
+	vars
+}
+
+const READONLY_UNSUPPORTED: &str =
+	"read-only preopens are not supported, WasmEdge 0.7 mounts every directory read-write";
+
+// `guest:host` or a single path mapped to itself
+fn parse_preopen(spec: &str) -> Result<PreopenConfig, String> {
+	let parts: Vec<&str> = spec.splitn(3, ':').collect();
+	let (guest, host) = match parts.as_slice() {
+		[dir] => (dir, dir),
+		[guest, host] => (guest, host),
+		[_, _, "readonly"] | [_, _, "ro"] => {
+			return Err(format!(
+				"Invalid preopen {}, {}",
+				spec, READONLY_UNSUPPORTED
+			))
+		}
+		_ => return Err(format!("Invalid preopen {}, expected guest:host", spec)),
+	};
+	if guest.is_empty() || host.is_empty() {
+		return Err(format!("Invalid preopen {}, expected guest:host", spec));
+	}
+	Ok(PreopenConfig {
+		guest: guest.to_string(),
+		host: host.to_string(),
+		readonly: false,
+	})
+}
+
+fn read_file(path: &Option<String>) -> Result<Option<String>, String> {
+	match path {
+		Some(p) => match fs::read_to_string(p) {
+			Ok(s) => Ok(Some(s)),
+			Err(e) => Err(format!("Failed to read {}. {}", p, e)),
+		},
+		None => Ok(None),
+	}
+}
+
+// Environment variables, secrets, program args and preopened directories
+// handed to every instance
+#[derive(Default)]
+pub struct Injected {
+	// The name of the Wasm file comes first
+	pub args: Vec<String>,
+	// KEY=VALUE entries of the WASI environment
+	pub env: Vec<String>,
+	// guest:host entries
+	pub preopens: Vec<String>,
+	secrets: HashMap<String, String>,
+}
+
+impl Injected {
+	pub fn new(config: &InjectConfig, wasi: &WasiConfig, args: &Args) -> Result<Injected, String> {
+		let dotenv = read_file(&config.dotenv)?
+			.map(|raw| parse_dotenv(&raw))
+			.unwrap_or_default();
+		let secrets_file: HashMap<String, String> = match read_file(&config.secrets_file)? {
+			Some(raw) => match toml::from_str(&raw) {
+				Ok(t) => t,
+				Err(e) => {
+					return Err(format!(
+						"Invalid secrets file {}. {}",
+						config.secrets_file.as_ref().unwrap(),
+						e
+					))
+				}
+			},
+			None => HashMap::new(),
+		};
+
+		let resolve = |var: &VarConfig| -> Result<String, String> {
+			if let Some(value) = &var.value {
+				return Ok(value.clone());
+			}
+			let key = var.key.as_ref().unwrap_or(&var.name);
+			let value = match var.from {
+				VarSource::Env => env::var(key).ok(),
+				VarSource::Dotenv => dotenv.get(key).cloned(),
+				VarSource::File => secrets_file.get(key).cloned(),
+			};
+			value.ok_or_else(|| {
+				format!(
+					"{} is not set, expected {} in {:?}",
+					var.name, key, var.from
+				)
+			})
+		};
+
+		let mut injected = Injected::default();
+
+		let program = Path::new(&args.wasm)
+			.file_name()
+			.map(|n| n.to_string_lossy().into_owned())
+			.unwrap_or_default();
+		injected.args.push(program);
+		injected.args.extend(wasi.args.iter().cloned());
+		injected.args.extend(args.wasi_args.iter().cloned());
+
+		let mut preopens = wasi.preopen.clone();
+		for spec in args.dirs.iter() {
+			preopens.push(parse_preopen(spec)?);
+		}
+		for p in preopens.iter() {
+			if p.readonly {
+				return Err(format!("Preopen {}: {}", p.guest, READONLY_UNSUPPORTED));
+			}
+			if !Path::new(&p.host).is_dir() {
+				return Err(format!("Preopened directory {} does not exist", p.host));
+			}
+			// WasmEdge splits the entry at the first `:`
+			injected.preopens.push(format!("{}:{}", p.guest, p.host));
+		}
+
+		for var in config.env.iter() {
+			injected.env.push(format!("{}={}", var.name, resolve(var)?));
+		}
+		for var in config.secret.iter() {
+			let value = resolve(var)?;
+			if var.wasi_env {
+				injected.env.push(format!("{}={}", var.name, value));
+			}
+			injected.secrets.insert(var.name.clone(), value);
+		}
+
+		let mut secrets = SECRETS.write().unwrap();
+		for value in injected.secrets.values() {
+			if !value.is_empty() && !secrets.contains(value) {
+				secrets.push(value.clone());
+			}
+		}
+		Ok(injected)
+	}
+
+	pub fn secret(&self, name: &str) -> Option<&String> {
+		self.secrets.get(name)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn dotenv() {
+		let vars = parse_dotenv(
+			"# comment\n\
+			 \n\
+			 PLAIN=value\n\
+			 export EXPORTED = spaced \n\
+			 DOUBLE=\"quoted value\"\n\
+			 SINGLE='single'\n\
+			 LONE=\"\n\
+			 EQUALS=a=b\n\
+			 EMPTY=\n\
+			 NO_VALUE\n",
+		);
+		let get = |k: &str| vars.get(k).map(|v| v.as_str());
+		assert_eq!(get("PLAIN"), Some("value"));
+		assert_eq!(get("EXPORTED"), Some("spaced"));
+		assert_eq!(get("DOUBLE"), Some("quoted value"));
+		assert_eq!(get("SINGLE"), Some("single"));
+		assert_eq!(get("LONE"), Some("\""));
+		assert_eq!(get("EQUALS"), Some("a=b"));
+		assert_eq!(get("EMPTY"), Some(""));
+		assert_eq!(get("NO_VALUE"), None);
+		assert_eq!(vars.len(), 7);
+	}
+
+	#[test]
+	fn preopens() {
+		let p = parse_preopen("/data:./fixtures").unwrap();
+		assert_eq!(
+			(p.guest.as_str(), p.host.as_str(), p.readonly),
+			("/data", "./fixtures", false)
+		);
+		let p = parse_preopen("/tmp").unwrap();
+		assert_eq!((p.guest.as_str(), p.host.as_str()), ("/tmp", "/tmp"));
+
+		for spec in ["/data:./fixtures:readonly", "/data:./fixtures:ro"] {
+			assert!(parse_preopen(spec).unwrap_err().contains("not supported"));
+		}
+		for spec in ["/data:./fixtures:rw", ":./fixtures", "/data:", ""] {
+			assert!(parse_preopen(spec).is_err(), "{}", spec);
+		}
+	}
+}
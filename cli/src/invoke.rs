@@ -0,0 +1,215 @@
+use clap::Args as ClapArgs;
+use reqwest::Url;
+use std::{collections::HashMap, fs, path::Path};
+use uuid::Uuid;
+
+use wasmhaiku_glue::{
+	context::RequestContext,
+	fileparts::{FilePart, FileParts},
+	headers::Headers,
+};
+
+use crate::cassette::Cassette;
+use crate::egress::Egress;
+use crate::initial::Args;
+use crate::inject::{redact, Injected};
+use crate::jobs::Job;
+use crate::kv::Kv;
+use crate::mocks::Mocks;
+use crate::outbound::Outbound;
+use crate::pool::Pool;
+use crate::route_config::Config;
+
+#[derive(ClapArgs, Debug)]
+pub struct InvokeArgs {
+	/// Name of the exported function to call
+	#[clap(short, long, value_parser)]
+	pub func: String,
+
+	/// Request header as `name: value`, or `@file` of one per line, may be repeated
+	#[clap(short = 'H', long = "header", value_parser)]
+	pub headers: Vec<String>,
+
+	/// Query parameter as `key=value`, or `@file` of one per line, may be repeated
+	#[clap(short, long = "query", value_parser)]
+	pub queries: Vec<String>,
+
+	/// Path parameter of the request context as `key=value`, may be repeated
+	#[clap(long = "path-param", value_parser)]
+	pub paths: Vec<String>,
+
+	/// Request body, or `@file` to read it from a file
+	#[clap(short = 'd', long, value_parser)]
+	pub body: Option<String>,
+
+	/// File to send as a file part, as `path` or `path;type=mime`, may be repeated
+	#[clap(short = 'F', long = "filepart", value_parser)]
+	pub fileparts: Vec<String>,
+
+	/// Method reported in the request context
+	#[clap(short = 'X', long, value_parser, default_value = "POST")]
+	pub method: String,
+
+	/// Base URL receiving the outbound requests instead of their original host
+	#[clap(long, value_parser)]
+	pub redirect_outbound: Option<Url>,
+}
+
+// Pairs in the order given, `@file` is replaced by the non-empty lines of the file
+fn split_pairs(pairs: &[String], sep: char) -> Result<Vec<(String, String)>, String> {
+	let mut split = vec![];
+	for p in pairs.iter() {
+		let lines = match p.strip_prefix('@') {
+			Some(path) => fs::read_to_string(path)
+				.map_err(|e| format!("Failed to read {}. {}", path, e))?
+				.lines()
+				.filter(|l| !l.trim().is_empty())
+				.map(String::from)
+				.collect(),
+			None => vec![p.clone()],
+		};
+		for l in lines.iter() {
+			match l.split_once(sep) {
+				Some((k, v)) => split.push((k.trim().to_string(), v.trim().to_string())),
+				None => return Err(format!("Expected `{}` in {:?}", sep, l)),
+			}
+		}
+	}
+	Ok(split)
+}
+
+fn read_body(body: &Option<String>) -> Result<Vec<u8>, String> {
+	match body {
+		Some(b) => match b.strip_prefix('@') {
+			Some(path) => fs::read(path).map_err(|e| format!("Failed to read {}. {}", path, e)),
+			None => Ok(b.as_bytes().to_vec()),
+		},
+		None => Ok(vec![]),
+	}
+}
+
+fn read_fileparts(fileparts: &[String]) -> Result<Vec<FilePart>, String> {
+	let mut parts = vec![];
+	for f in fileparts.iter() {
+		let (path, mime_str) = match f.split_once(";type=") {
+			Some((path, mime_str)) => (path, mime_str),
+			None => (f.as_str(), "application/octet-stream"),
+		};
+		let bytes = match fs::read(path) {
+			Ok(b) => b,
+			Err(e) => return Err(format!("Failed to read {}. {}", path, e)),
+		};
+		parts.push(FilePart {
+			file_name: Path::new(path)
+				.file_name()
+				.map(|n| n.to_string_lossy().into_owned())
+				.unwrap_or_default(),
+			mime_str: mime_str.to_string(),
+			bytes,
+		});
+	}
+	Ok(parts)
+}
+
+fn run(args: &Args, invoke: &InvokeArgs) -> Result<(u16, String, Vec<u8>), String> {
+	let config = Config::new(args.config.clone())?;
+
+	let mut headers = Headers::new();
+	for (k, v) in split_pairs(&invoke.headers, ':')? {
+		headers.append(k.as_str(), v);
+	}
+	let headers = headers.to_string();
+	let queries: HashMap<String, String> = split_pairs(&invoke.queries, '=')?.into_iter().collect();
+	let queries = serde_json::to_string(&queries).unwrap();
+	let context = RequestContext {
+		method: invoke.method.clone(),
+		route: String::new(),
+		uri: String::new(),
+		paths: split_pairs(&invoke.paths, '=')?.into_iter().collect(),
+		remote_addr: String::new(),
+		request_id: Uuid::new_v4().to_string(),
+	}
+	.to_string();
+	let body = read_body(&invoke.body)?;
+
+	let outbound = Outbound::new(
+		invoke.redirect_outbound.clone(),
+		Egress::new(&config),
+		Mocks::new(&args.config, &config, args.mocks.as_ref())?,
+		Cassette::from_args(args)?,
+		&config.outbound,
+	)?;
+	let kv = Kv::new(&config.kv, &args.wasm)?;
+	let injected = Injected::new(&config.inject, &config.wasi, args)?;
+	let pool = Pool::new(
+		args.wasm.clone(),
+		config.pool.clone(),
+		outbound,
+		kv,
+		injected,
+	);
+	let wasm = pool.checkout()?;
+
+	let fileparts = if invoke.fileparts.is_empty() {
+		None
+	} else {
+		let inner = read_fileparts(&invoke.fileparts)?;
+		Some(FileParts { inner }.to_vec())
+	};
+	wasm.execute(&Job {
+		func_name: invoke.func.clone(),
+		headers,
+		queries,
+		context,
+		body,
+		fileparts,
+	})
+}
+
+// Print the returned status, headers and body and return whether the call succeeded
+pub fn invoke(args: &Args, invoke: &InvokeArgs) -> bool {
+	match run(args, invoke) {
+		Ok((status, headers, body)) => {
+			println!("{}", status);
+			let headers: HashMap<String, String> =
+				serde_json::from_str(headers.as_str()).unwrap_or_default();
+			for (k, v) in headers.iter() {
+				println!("{}: {}", k, v);
+			}
+			println!();
+			println!("{}", String::from_utf8_lossy(&body));
+			true
+		}
+		Err(e) => {
+			eprintln!("{}", redact(&e));
+			false
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::{env, process};
+
+	#[test]
+	fn pairs() {
+		let path = env::temp_dir().join(format!("haiku-headers-{}", process::id()));
+		fs::write(&path, "Accept: text/plain\n\nX-Id: 2\n").unwrap();
+		let headers = [String::from("X-Id: 1"), format!("@{}", path.display())];
+		let split = split_pairs(&headers, ':').unwrap();
+		fs::remove_file(&path).unwrap();
+
+		// repeated names are all kept, in order
+		let pairs: Vec<(&str, &str)> = split
+			.iter()
+			.map(|(k, v)| (k.as_str(), v.as_str()))
+			.collect();
+		assert_eq!(
+			pairs,
+			[("X-Id", "1"), ("Accept", "text/plain"), ("X-Id", "2")]
+		);
+
+		assert!(split_pairs(&[String::from("key")], '=').is_err());
+	}
+}
@@ -0,0 +1,510 @@
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+use serde_json::Value;
+use std::{
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		Arc, Mutex,
+	},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+	sync::{Notify, Semaphore},
+	time::{self, Instant},
+};
+use uuid::Uuid;
+
+use crate::inject::redact;
+use crate::pool::Pool;
+use crate::route_config::QueueConfig;
+use crate::tasks::TASKS;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS jobs (
+	id TEXT PRIMARY KEY,
+	func_name TEXT NOT NULL,
+	headers TEXT NOT NULL,
+	queries TEXT NOT NULL,
+	context TEXT NOT NULL,
+	body BLOB NOT NULL,
+	fileparts BLOB,
+	state TEXT NOT NULL,
+	attempts INTEGER NOT NULL DEFAULT 0,
+	run_at INTEGER NOT NULL,
+	last_error TEXT,
+	result_status INTEGER,
+	result_headers TEXT,
+	result_body BLOB,
+	created_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS jobs_due ON jobs (state, run_at);
+";
+
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Bodies that aren't text are stored as they are
+fn redact_bytes(body: Vec<u8>) -> Vec<u8> {
+	match String::from_utf8(body) {
+		Ok(s) => redact(&s).into_bytes(),
+		Err(e) => e.into_bytes(),
+	}
+}
+
+pub struct Job {
+	pub func_name: String,
+	pub headers: String,
+	pub queries: String,
+	pub context: String,
+	pub body: Vec<u8>,
+	pub fileparts: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeadLetter {
+	pub id: String,
+	pub func_name: String,
+	pub attempts: u32,
+	pub last_error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JobStatus {
+	pub id: String,
+	pub func_name: String,
+	pub state: String,
+	pub attempts: u32,
+	pub status: Option<u16>,
+	pub headers: Option<Value>,
+	pub body: Option<String>,
+	// "utf-8" or "base64" for bodies that aren't text
+	pub body_encoding: Option<&'static str>,
+	pub error: Option<String>,
+}
+
+pub struct JobQueue {
+	conn: Mutex<Connection>,
+	config: QueueConfig,
+	wake: Notify,
+	stopped: AtomicBool,
+}
+
+fn encode_body(body: Vec<u8>) -> (String, &'static str) {
+	match String::from_utf8(body) {
+		Ok(s) => (s, "utf-8"),
+		Err(e) => (base64::encode(e.as_bytes()), "base64"),
+	}
+}
+
+// Dead letters are reported as failed
+fn public_state(state: String) -> String {
+	match state.as_str() {
+		"dead" => String::from("failed"),
+		_ => state,
+	}
+}
+
+fn now_millis() -> i64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.unwrap()
+		.as_millis() as i64
+}
+
+impl JobQueue {
+	pub fn new(config: QueueConfig) -> JobQueue {
+		let conn = Connection::open(&config.path).unwrap();
+		conn.execute_batch(SCHEMA).unwrap();
+		// jobs interrupted by a crash or a shutdown deadline are replayed
+		conn.execute(
+			"UPDATE jobs SET state = 'queued' WHERE state = 'running'",
+			[],
+		)
+		.unwrap();
+
+		JobQueue {
+			conn: Mutex::new(conn),
+			config,
+			wake: Notify::new(),
+			stopped: AtomicBool::new(false),
+		}
+	}
+
+	pub fn enqueue(&self, job: Job) -> Result<String, String> {
+		let id = Uuid::new_v4().to_string();
+		let now = now_millis();
+		let conn = self.conn.lock().unwrap();
+		match conn.execute(
+			"INSERT INTO jobs (id, func_name, headers, queries, context, body, fileparts, state, run_at, created_at)
+			VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, 'queued', ?8, ?8)",
+			params![
+				id,
+				job.func_name,
+				job.headers,
+				job.queries,
+				job.context,
+				job.body,
+				job.fileparts,
+				now
+			],
+		) {
+			Ok(_) => {
+				self.wake.notify_one();
+				Ok(id)
+			}
+			Err(e) => Err(format!("Failed to enqueue the job. {:?}", e)),
+		}
+	}
+
+	fn claim(&self) -> Option<(String, u32, Job)> {
+		let conn = self.conn.lock().unwrap();
+		let claimed = conn
+			.query_row(
+				"SELECT id, attempts, func_name, headers, queries, context, body, fileparts
+				FROM jobs WHERE state = 'queued' AND run_at <= ?1 ORDER BY run_at LIMIT 1",
+				params![now_millis()],
+				|row| {
+					Ok((
+						row.get::<_, String>(0)?,
+						row.get::<_, u32>(1)? + 1,
+						Job {
+							func_name: row.get(2)?,
+							headers: row.get(3)?,
+							queries: row.get(4)?,
+							context: row.get(5)?,
+							body: row.get(6)?,
+							fileparts: row.get(7)?,
+						},
+					))
+				},
+			)
+			.optional();
+		let claimed = match claimed {
+			Ok(claimed) => claimed?,
+			Err(e) => {
+				eprintln!("Failed to claim a job. {:?}", e);
+				return None;
+			}
+		};
+
+		// a job left queued would be claimed twice
+		if let Err(e) = conn.execute(
+			"UPDATE jobs SET state = 'running', attempts = ?2 WHERE id = ?1",
+			params![claimed.0, claimed.1],
+		) {
+			eprintln!("Failed to claim the job {}. {:?}", claimed.0, e);
+			return None;
+		}
+		Some(claimed)
+	}
+
+	// Finished jobs are kept for retention_ms after they were queued, 0 keeps them
+	fn purge(&self) {
+		if self.config.retention_ms == 0 {
+			return;
+		}
+		let before = now_millis().saturating_sub(self.config.retention_ms as i64);
+		let conn = self.conn.lock().unwrap();
+		if let Err(e) = conn.execute(
+			"DELETE FROM jobs WHERE state IN ('succeeded', 'dead') AND created_at < ?1",
+			params![before],
+		) {
+			eprintln!("Failed to purge the finished jobs. {:?}", e);
+		}
+	}
+
+	// Persisted redacted, the stored result and error are served back to callers
+	fn settle(&self, id: &str, attempts: u32, ret: Result<(u16, String, Vec<u8>), String>) {
+		let ret = ret
+			.map(|(status, headers, body)| (status, redact(&headers), redact_bytes(body)))
+			.map_err(|e| redact(&e));
+		let (result, error) = match ret {
+			Ok((status, headers, body)) if status < 500 => (Some((status, headers, body)), None),
+			Ok((status, headers, body)) => {
+				let error = format!(
+					"Returned status {}. {}",
+					status,
+					String::from_utf8_lossy(&body)
+				);
+				(Some((status, headers, body)), Some(error))
+			}
+			Err(e) => (None, Some(e)),
+		};
+
+		let conn = self.conn.lock().unwrap();
+		if let Some((status, headers, body)) = result {
+			let _ = conn.execute(
+				"UPDATE jobs SET result_status = ?2, result_headers = ?3, result_body = ?4 WHERE id = ?1",
+				params![id, status, headers, body],
+			);
+		}
+		let _ = match error {
+			None => conn.execute(
+				"UPDATE jobs SET state = 'succeeded', last_error = NULL WHERE id = ?1",
+				params![id],
+			),
+			Some(e) if attempts >= self.config.max_attempts => conn.execute(
+				"UPDATE jobs SET state = 'dead', last_error = ?2 WHERE id = ?1",
+				params![id, e],
+			),
+			Some(e) => conn.execute(
+				"UPDATE jobs SET state = 'queued', last_error = ?2, run_at = ?3 WHERE id = ?1",
+				params![id, e, now_millis() + self.backoff(attempts) as i64],
+			),
+		};
+	}
+
+	fn backoff(&self, attempts: u32) -> u64 {
+		let factor = 1u64 << attempts.saturating_sub(1).min(32);
+		self.config
+			.backoff_base_ms
+			.saturating_mul(factor)
+			.min(self.config.backoff_max_ms)
+	}
+
+	pub async fn run(&'static self, pool: fn() -> Arc<Pool>) {
+		let permits = Arc::new(Semaphore::new(self.config.concurrency.max(1)));
+		let poll_interval = Duration::from_millis(self.config.poll_interval_ms);
+		let mut purged = Instant::now();
+		self.purge();
+
+		while !self.stopped.load(Ordering::SeqCst) {
+			if purged.elapsed() >= PURGE_INTERVAL {
+				purged = Instant::now();
+				self.purge();
+			}
+			let permit = permits.clone().acquire_owned().await.unwrap();
+			match self.claim() {
+				Some((id, attempts, job)) => {
+					TASKS.spawn(async move {
+						let ret = tokio::task::spawn_blocking(move || {
+							let pool = pool();
+							let wasm = pool.checkout()?;
+							wasm.execute(&job)
+						})
+						.await
+						.unwrap_or_else(|e| Err(format!("{:?}", e)));
+						self.settle(&id, attempts, ret);
+						drop(permit);
+					});
+				}
+				None => {
+					drop(permit);
+					let _ = time::timeout(poll_interval, self.wake.notified()).await;
+				}
+			}
+		}
+	}
+
+	// Stop claiming jobs, whatever is still queued is replayed on the next start
+	pub fn stop(&self) {
+		self.stopped.store(true, Ordering::SeqCst);
+		self.wake.notify_one();
+	}
+
+	pub fn status(&self, id: &str) -> Option<JobStatus> {
+		let conn = self.conn.lock().unwrap();
+		conn.query_row(
+			"SELECT id, func_name, state, attempts, result_status, result_headers, result_body, last_error
+			FROM jobs WHERE id = ?1",
+			params![id],
+			|row| {
+				let state: String = row.get(2)?;
+				let headers: Option<String> = row.get(5)?;
+				let body = row.get::<_, Option<Vec<u8>>>(6)?.map(encode_body);
+				Ok(JobStatus {
+					id: row.get(0)?,
+					func_name: row.get(1)?,
+					state: public_state(state),
+					attempts: row.get(3)?,
+					status: row.get(4)?,
+					headers: headers.map(|h| serde_json::from_str(&h).unwrap_or(Value::String(h))),
+					body_encoding: body.as_ref().map(|(_, encoding)| *encoding),
+					body: body.map(|(body, _)| body),
+					error: row.get(7)?,
+				})
+			},
+		)
+		.optional()
+		.unwrap_or_default()
+	}
+
+	pub fn dead_letters(&self) -> Vec<DeadLetter> {
+		let conn = self.conn.lock().unwrap();
+		let mut stmt = match conn.prepare(
+			"SELECT id, func_name, attempts, last_error FROM jobs WHERE state = 'dead' ORDER BY created_at",
+		) {
+			Ok(s) => s,
+			Err(_) => return vec![],
+		};
+		let rows = stmt.query_map([], |row| {
+			Ok(DeadLetter {
+				id: row.get(0)?,
+				func_name: row.get(1)?,
+				attempts: row.get(2)?,
+				last_error: row.get(3)?,
+			})
+		});
+		match rows {
+			Ok(rows) => rows.filter_map(|r| r.ok()).collect(),
+			Err(_) => vec![],
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::{env, process};
+
+	fn queue(path: &str) -> JobQueue {
+		JobQueue::new(QueueConfig {
+			path: path.to_string(),
+			max_attempts: 2,
+			..Default::default()
+		})
+	}
+
+	fn job() -> Job {
+		Job {
+			func_name: String::from("f"),
+			headers: String::from("{}"),
+			queries: String::from("{}"),
+			context: String::from("{}"),
+			body: b"body".to_vec(),
+			fileparts: None,
+		}
+	}
+
+	// Make the job due now, whatever its backoff
+	fn due(queue: &JobQueue, id: &str) {
+		let conn = queue.conn.lock().unwrap();
+		conn.execute("UPDATE jobs SET run_at = 0 WHERE id = ?1", params![id])
+			.unwrap();
+	}
+
+	#[test]
+	fn backoff() {
+		let queue = queue(":memory:");
+		assert_eq!(queue.backoff(1), 1000);
+		assert_eq!(queue.backoff(2), 2000);
+		assert_eq!(queue.backoff(3), 4000);
+		assert_eq!(queue.backoff(10), 300000);
+		assert_eq!(queue.backoff(u32::MAX), 300000);
+	}
+
+	#[test]
+	fn settle() {
+		let queue = queue(":memory:");
+		let id = queue.enqueue(job()).unwrap();
+
+		let (claimed, attempts, _) = queue.claim().unwrap();
+		assert_eq!((claimed.as_str(), attempts), (id.as_str(), 1));
+		assert!(queue.claim().is_none());
+
+		// a status of 500 and above is retried after the backoff
+		queue.settle(
+			&id,
+			attempts,
+			Ok((503, String::from("{}"), b"busy".to_vec())),
+		);
+		let status = queue.status(&id).unwrap();
+		assert_eq!(
+			(status.state.as_str(), status.status),
+			("queued", Some(503))
+		);
+		assert_eq!(status.error.unwrap(), "Returned status 503. busy");
+		assert!(queue.claim().is_none());
+
+		// until max_attempts, then the job is dead and reported as failed
+		due(&queue, &id);
+		let (_, attempts, _) = queue.claim().unwrap();
+		assert_eq!(attempts, 2);
+		queue.settle(&id, attempts, Err(String::from("trapped")));
+		let status = queue.status(&id).unwrap();
+		assert_eq!((status.state.as_str(), status.attempts), ("failed", 2));
+		assert_eq!(status.error.unwrap(), "trapped");
+		let dead: Vec<String> = queue.dead_letters().into_iter().map(|d| d.id).collect();
+		assert_eq!(dead, [id]);
+
+		let id = queue.enqueue(job()).unwrap();
+		let (_, attempts, _) = queue.claim().unwrap();
+		queue.settle(&id, attempts, Ok((404, String::from("{}"), vec![])));
+		let status = queue.status(&id).unwrap();
+		assert_eq!(
+			(status.state.as_str(), status.status),
+			("succeeded", Some(404))
+		);
+		assert!(status.error.is_none());
+	}
+
+	#[test]
+	fn states() {
+		assert_eq!(public_state(String::from("dead")), "failed");
+		for state in ["queued", "running", "succeeded"] {
+			assert_eq!(public_state(String::from(state)), state);
+		}
+	}
+
+	#[test]
+	fn binary_body() {
+		let queue = queue(":memory:");
+		let id = queue.enqueue(job()).unwrap();
+		let (_, attempts, _) = queue.claim().unwrap();
+		let body = vec![0xff, 0x00, 0x80];
+		queue.settle(&id, attempts, Ok((200, String::from("{}"), body)));
+		let status = queue.status(&id).unwrap();
+		assert_eq!(status.body.unwrap(), "/wCA");
+		assert_eq!(status.body_encoding, Some("base64"));
+
+		assert_eq!(
+			encode_body(b"text".to_vec()),
+			(String::from("text"), "utf-8")
+		);
+	}
+
+	#[test]
+	fn replay() {
+		let path = env::temp_dir().join(format!("haiku-jobs-{}.db", process::id()));
+		let path = path.to_str().unwrap();
+
+		let id = queue(path).enqueue(job()).unwrap();
+		let running = queue(path);
+		let (_, attempts, _) = running.claim().unwrap();
+		assert_eq!(attempts, 1);
+		drop(running);
+
+		// a job still running when the process stopped is claimed again
+		let restarted = queue(path);
+		let (claimed, attempts, job) = restarted.claim().unwrap();
+		assert_eq!((claimed.as_str(), attempts), (id.as_str(), 2));
+		assert_eq!(job.body, b"body");
+
+		drop(restarted);
+		std::fs::remove_file(path).unwrap();
+	}
+
+	#[test]
+	fn purge() {
+		let queue = JobQueue::new(QueueConfig {
+			path: String::from(":memory:"),
+			retention_ms: 1000,
+			..Default::default()
+		});
+		let queued = queue.enqueue(job()).unwrap();
+		let succeeded = queue.enqueue(job()).unwrap();
+		due(&queue, &succeeded);
+		let (claimed, attempts, _) = queue.claim().unwrap();
+		queue.settle(&claimed, attempts, Ok((200, String::from("{}"), vec![])));
+		queue
+			.conn
+			.lock()
+			.unwrap()
+			.execute("UPDATE jobs SET created_at = 0", [])
+			.unwrap();
+
+		// only finished jobs are purged
+		queue.purge();
+		assert!(queue.status(&succeeded).is_none());
+		assert!(queue.status(&queued).is_some());
+	}
+}
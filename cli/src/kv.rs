@@ -0,0 +1,449 @@
+use lazy_static::lazy_static;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::{
+	collections::{BTreeMap, HashMap},
+	fs,
+	path::Path,
+	sync::{Arc, Mutex},
+	time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::route_config::{KvBackend, KvConfig};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS kv (
+	namespace TEXT NOT NULL,
+	key TEXT NOT NULL,
+	value BLOB NOT NULL,
+	expires_at INTEGER,
+	PRIMARY KEY (namespace, key)
+);
+";
+
+lazy_static! {
+	// Opened stores outlive reloads so the guests keep their state
+	static ref STORES: Mutex<HashMap<String, Arc<dyn Store>>> = Mutex::new(HashMap::new());
+}
+
+fn now_millis() -> i64 {
+	SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.unwrap()
+		.as_millis() as i64
+}
+
+// Expired entries are never returned, the stores drop them lazily
+pub trait Store: Send + Sync {
+	fn get(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>, String>;
+	fn set(
+		&self,
+		namespace: &str,
+		key: &str,
+		value: Vec<u8>,
+		expires_at: Option<i64>,
+	) -> Result<(), String>;
+	fn delete(&self, namespace: &str, key: &str) -> Result<bool, String>;
+	fn list_prefix(&self, namespace: &str, prefix: &str) -> Result<Vec<String>, String>;
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct Entry {
+	value: Vec<u8>,
+	expires_at: Option<i64>,
+}
+
+impl Entry {
+	fn is_live(&self, now: i64) -> bool {
+		self.expires_at.map(|e| e > now).unwrap_or(true)
+	}
+}
+
+type Entries = BTreeMap<(String, String), Entry>;
+
+#[derive(Default)]
+pub struct MemoryStore {
+	entries: Mutex<Entries>,
+}
+
+fn get_entry(entries: &mut Entries, namespace: &str, key: &str) -> Option<Vec<u8>> {
+	let k = (namespace.to_string(), key.to_string());
+	match entries.get(&k) {
+		Some(e) if e.is_live(now_millis()) => Some(e.value.clone()),
+		Some(_) => {
+			entries.remove(&k);
+			None
+		}
+		None => None,
+	}
+}
+
+fn list_entries(entries: &Entries, namespace: &str, prefix: &str) -> Vec<String> {
+	let now = now_millis();
+	entries
+		.range((namespace.to_string(), prefix.to_string())..)
+		.take_while(|((ns, k), _)| ns == namespace && k.starts_with(prefix))
+		.filter(|(_, e)| e.is_live(now))
+		.map(|((_, k), _)| k.clone())
+		.collect()
+}
+
+impl Store for MemoryStore {
+	fn get(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>, String> {
+		Ok(get_entry(&mut self.entries.lock().unwrap(), namespace, key))
+	}
+
+	fn set(
+		&self,
+		namespace: &str,
+		key: &str,
+		value: Vec<u8>,
+		expires_at: Option<i64>,
+	) -> Result<(), String> {
+		self.entries.lock().unwrap().insert(
+			(namespace.to_string(), key.to_string()),
+			Entry { value, expires_at },
+		);
+		Ok(())
+	}
+
+	fn delete(&self, namespace: &str, key: &str) -> Result<bool, String> {
+		let mut entries = self.entries.lock().unwrap();
+		let k = (namespace.to_string(), key.to_string());
+		Ok(entries
+			.remove(&k)
+			.map(|e| e.is_live(now_millis()))
+			.unwrap_or(false))
+	}
+
+	fn list_prefix(&self, namespace: &str, prefix: &str) -> Result<Vec<String>, String> {
+		Ok(list_entries(
+			&self.entries.lock().unwrap(),
+			namespace,
+			prefix,
+		))
+	}
+}
+
+// Held in memory and written out as JSON after every change
+pub struct FileStore {
+	path: String,
+	entries: Mutex<Entries>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct FileEntry {
+	namespace: String,
+	key: String,
+	#[serde(flatten)]
+	entry: Entry,
+}
+
+impl FileStore {
+	pub fn open(path: &str) -> Result<FileStore, String> {
+		let mut entries = Entries::new();
+		if Path::new(path).exists() {
+			let raw = match fs::read_to_string(path) {
+				Ok(s) => s,
+				Err(e) => return Err(format!("Failed to read {}. {}", path, e)),
+			};
+			let file_entries: Vec<FileEntry> = match serde_json::from_str(&raw) {
+				Ok(v) => v,
+				Err(e) => return Err(format!("Invalid kv file {}. {}", path, e)),
+			};
+			for f in file_entries.into_iter() {
+				entries.insert((f.namespace, f.key), f.entry);
+			}
+		}
+		Ok(FileStore {
+			path: path.to_string(),
+			entries: Mutex::new(entries),
+		})
+	}
+
+	// Written to a temporary file first so a crash never leaves half a file
+	fn save(&self, entries: &Entries) -> Result<(), String> {
+		let now = now_millis();
+		let file_entries: Vec<FileEntry> = entries
+			.iter()
+			.filter(|(_, e)| e.is_live(now))
+			.map(|((namespace, key), entry)| FileEntry {
+				namespace: namespace.clone(),
+				key: key.clone(),
+				entry: entry.clone(),
+			})
+			.collect();
+		let tmp = format!("{}.tmp", self.path);
+		if let Err(e) = fs::write(&tmp, serde_json::to_vec(&file_entries).unwrap()) {
+			return Err(format!("Failed to write {}. {}", tmp, e));
+		}
+		match fs::rename(&tmp, &self.path) {
+			Ok(_) => Ok(()),
+			Err(e) => Err(format!("Failed to write {}. {}", self.path, e)),
+		}
+	}
+}
+
+impl Store for FileStore {
+	fn get(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>, String> {
+		Ok(get_entry(&mut self.entries.lock().unwrap(), namespace, key))
+	}
+
+	fn set(
+		&self,
+		namespace: &str,
+		key: &str,
+		value: Vec<u8>,
+		expires_at: Option<i64>,
+	) -> Result<(), String> {
+		let mut entries = self.entries.lock().unwrap();
+		entries.insert(
+			(namespace.to_string(), key.to_string()),
+			Entry { value, expires_at },
+		);
+		self.save(&entries)
+	}
+
+	fn delete(&self, namespace: &str, key: &str) -> Result<bool, String> {
+		let mut entries = self.entries.lock().unwrap();
+		let k = (namespace.to_string(), key.to_string());
+		match entries.remove(&k) {
+			Some(e) => {
+				self.save(&entries)?;
+				Ok(e.is_live(now_millis()))
+			}
+			None => Ok(false),
+		}
+	}
+
+	fn list_prefix(&self, namespace: &str, prefix: &str) -> Result<Vec<String>, String> {
+		Ok(list_entries(
+			&self.entries.lock().unwrap(),
+			namespace,
+			prefix,
+		))
+	}
+}
+
+pub struct SqliteStore {
+	conn: Mutex<Connection>,
+}
+
+impl SqliteStore {
+	pub fn open(path: &str) -> Result<SqliteStore, String> {
+		let conn = match Connection::open(path) {
+			Ok(c) => c,
+			Err(e) => return Err(format!("Failed to open {}. {}", path, e)),
+		};
+		if let Err(e) = conn.execute_batch(SCHEMA) {
+			return Err(format!("Failed to create the kv table in {}. {}", path, e));
+		}
+		Ok(SqliteStore {
+			conn: Mutex::new(conn),
+		})
+	}
+}
+
+impl Store for SqliteStore {
+	fn get(&self, namespace: &str, key: &str) -> Result<Option<Vec<u8>>, String> {
+		let conn = self.conn.lock().unwrap();
+		conn.query_row(
+			"SELECT value FROM kv WHERE namespace = ?1 AND key = ?2
+			AND (expires_at IS NULL OR expires_at > ?3)",
+			params![namespace, key, now_millis()],
+			|row| row.get(0),
+		)
+		.optional()
+		.map_err(|e| format!("{:?}", e))
+	}
+
+	fn set(
+		&self,
+		namespace: &str,
+		key: &str,
+		value: Vec<u8>,
+		expires_at: Option<i64>,
+	) -> Result<(), String> {
+		let conn = self.conn.lock().unwrap();
+		match conn.execute(
+			"INSERT OR REPLACE INTO kv (namespace, key, value, expires_at) VALUES (?1, ?2, ?3, ?4)",
+			params![namespace, key, value, expires_at],
+		) {
+			Ok(_) => Ok(()),
+			Err(e) => Err(format!("{:?}", e)),
+		}
+	}
+
+	fn delete(&self, namespace: &str, key: &str) -> Result<bool, String> {
+		let conn = self.conn.lock().unwrap();
+		// expired entries are removed as well but don't count as deleted
+		match conn.execute(
+			"DELETE FROM kv WHERE namespace = ?1 AND key = ?2 AND (expires_at IS NULL OR expires_at > ?3)",
+			params![namespace, key, now_millis()],
+		) {
+			Ok(n) => {
+				_ = conn.execute(
+					"DELETE FROM kv WHERE namespace = ?1 AND key = ?2",
+					params![namespace, key],
+				);
+				Ok(n > 0)
+			}
+			Err(e) => Err(format!("{:?}", e)),
+		}
+	}
+
+	fn list_prefix(&self, namespace: &str, prefix: &str) -> Result<Vec<String>, String> {
+		let conn = self.conn.lock().unwrap();
+		let mut stmt = match conn.prepare(
+			"SELECT key FROM kv WHERE namespace = ?1 AND substr(key, 1, length(?2)) = ?2
+			AND (expires_at IS NULL OR expires_at > ?3) ORDER BY key",
+		) {
+			Ok(s) => s,
+			Err(e) => return Err(format!("{:?}", e)),
+		};
+		let rows = match stmt.query_map(params![namespace, prefix, now_millis()], |row| row.get(0))
+		{
+			Ok(r) => r,
+			Err(e) => return Err(format!("{:?}", e)),
+		};
+		rows.collect::<Result<Vec<String>, _>>()
+			.map_err(|e| format!("{:?}", e))
+	}
+}
+
+fn open(config: &KvConfig) -> Result<Arc<dyn Store>, String> {
+	let path = config.path.clone().unwrap_or_else(|| match config.backend {
+		KvBackend::Sqlite => String::from("haiku-kv.db"),
+		_ => String::from("haiku-kv.json"),
+	});
+	let id = match config.backend {
+		KvBackend::Memory => String::from("memory"),
+		KvBackend::File => format!("file:{}", path),
+		KvBackend::Sqlite => format!("sqlite:{}", path),
+	};
+
+	let mut stores = STORES.lock().unwrap();
+	if let Some(store) = stores.get(&id) {
+		return Ok(store.clone());
+	}
+	let store: Arc<dyn Store> = match config.backend {
+		KvBackend::Memory => Arc::new(MemoryStore::default()),
+		KvBackend::File => Arc::new(FileStore::open(&path)?),
+		KvBackend::Sqlite => Arc::new(SqliteStore::open(&path)?),
+	};
+	stores.insert(id, store.clone());
+	Ok(store)
+}
+
+// The store as seen by one connector, its keys never meet another's
+pub struct Kv {
+	namespace: String,
+	store: Arc<dyn Store>,
+}
+
+impl Kv {
+	// The namespace defaults to the name of the Wasm file
+	pub fn new(config: &KvConfig, wasm_path: &str) -> Result<Kv, String> {
+		let namespace = config.namespace.clone().unwrap_or_else(|| {
+			Path::new(wasm_path)
+				.file_stem()
+				.map(|s| s.to_string_lossy().into_owned())
+				.unwrap_or_default()
+		});
+		Ok(Kv {
+			namespace,
+			store: open(config)?,
+		})
+	}
+
+	pub fn get(&self, key: &str) -> Result<Option<Vec<u8>>, String> {
+		self.store.get(&self.namespace, key)
+	}
+
+	pub fn set(&self, key: &str, value: Vec<u8>, ttl: Option<Duration>) -> Result<(), String> {
+		let expires_at = ttl.map(|t| now_millis() + t.as_millis() as i64);
+		self.store.set(&self.namespace, key, value, expires_at)
+	}
+
+	pub fn delete(&self, key: &str) -> Result<bool, String> {
+		self.store.delete(&self.namespace, key)
+	}
+
+	pub fn list_prefix(&self, prefix: &str) -> Result<Vec<String>, String> {
+		self.store.list_prefix(&self.namespace, prefix)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::{env, process};
+
+	// The same behaviour is expected of every backend
+	fn exercise(store: &dyn Store) {
+		let past = Some(now_millis() - 1000);
+		let future = Some(now_millis() + 60000);
+
+		store.set("n", "a", b"1".to_vec(), None).unwrap();
+		store.set("n", "ab", b"2".to_vec(), future).unwrap();
+		store.set("n", "abc", b"3".to_vec(), None).unwrap();
+		store.set("n", "abd", b"4".to_vec(), past).unwrap();
+		store.set("n", "b", b"5".to_vec(), None).unwrap();
+		// keys of neighbouring namespaces sort right around the ones of `n`
+		store.set("m", "ab", b"6".to_vec(), None).unwrap();
+		store.set("n2", "ab", b"7".to_vec(), None).unwrap();
+
+		assert_eq!(store.get("n", "ab").unwrap(), Some(b"2".to_vec()));
+		assert_eq!(store.get("m", "ab").unwrap(), Some(b"6".to_vec()));
+		assert_eq!(store.get("n2", "ab").unwrap(), Some(b"7".to_vec()));
+		assert_eq!(store.get("n", "missing").unwrap(), None);
+
+		// expired entries are never returned
+		assert_eq!(store.get("n", "abd").unwrap(), None);
+		assert_eq!(store.list_prefix("n", "ab").unwrap(), ["ab", "abc"]);
+		assert!(!store.delete("n", "abd").unwrap());
+
+		assert_eq!(store.list_prefix("n", "").unwrap(), ["a", "ab", "abc", "b"]);
+		assert_eq!(store.list_prefix("n", "abc").unwrap(), ["abc"]);
+		assert!(store.list_prefix("n", "c").unwrap().is_empty());
+		assert_eq!(store.list_prefix("n2", "").unwrap(), ["ab"]);
+
+		store.set("n", "a", b"8".to_vec(), None).unwrap();
+		assert_eq!(store.get("n", "a").unwrap(), Some(b"8".to_vec()));
+		assert!(store.delete("n", "a").unwrap());
+		assert!(!store.delete("n", "a").unwrap());
+		assert_eq!(store.get("n", "a").unwrap(), None);
+		assert_eq!(store.get("m", "ab").unwrap(), Some(b"6".to_vec()));
+	}
+
+	#[test]
+	fn memory() {
+		exercise(&MemoryStore::default());
+	}
+
+	#[test]
+	fn sqlite() {
+		exercise(&SqliteStore::open(":memory:").unwrap());
+	}
+
+	#[test]
+	fn file() {
+		let dir = env::temp_dir().join(format!("haiku-kv-test-{}", process::id()));
+		// left over by an earlier run that failed
+		_ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+		let path = dir.join("kv.json").to_string_lossy().into_owned();
+
+		exercise(&FileStore::open(&path).unwrap());
+
+		// the entries left are read back, except the expired ones
+		let reopened = FileStore::open(&path).unwrap();
+		assert_eq!(reopened.list_prefix("n", "").unwrap(), ["ab", "abc", "b"]);
+		assert_eq!(reopened.get("n", "b").unwrap(), Some(b"5".to_vec()));
+		assert_eq!(reopened.get("m", "ab").unwrap(), Some(b"6".to_vec()));
+		assert_eq!(reopened.get("n", "abd").unwrap(), None);
+		assert!(!Path::new(&format!("{}.tmp", path)).exists());
+
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
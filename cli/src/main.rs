@@ -1,28 +1,90 @@
+mod cassette;
+mod egress;
 mod initial;
+mod inject;
+mod invoke;
+mod jobs;
+mod kv;
+mod mocks;
+mod outbound;
+mod pool;
 mod route_config;
+mod tasks;
+mod validate;
 mod wasm;
 
-use std::{collections::HashMap, env, future::Future, net::SocketAddr, pin::Pin};
+use std::{
+	collections::HashMap,
+	env, fs,
+	future::Future,
+	net::SocketAddr,
+	pin::Pin,
+	process,
+	sync::{Arc, Mutex, PoisonError},
+	time::Duration,
+};
 
 use axum::{
-	body::Bytes,
-	extract::{ContentLengthLimit, Multipart, Query},
+	body::{Body, Bytes},
+	extract::{ConnectInfo, ContentLengthLimit, Multipart, Path, Query},
 	handler::Handler,
 	http::{
-		header::{HeaderMap, HeaderName, HeaderValue},
-		StatusCode,
+		header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION},
+		Method, Request, StatusCode, Uri,
 	},
+	response::Response,
 	routing::{self, MethodFilter},
-	Router,
+	Json, Router,
 };
+use axum_server::{tls_rustls::RustlsConfig, Handle};
+use clap::Parser;
+use hyper::service::Service;
 use lazy_static::lazy_static;
+use tokio::{
+	signal::unix::{signal, SignalKind},
+	time::{self, Instant},
+};
+use uuid::Uuid;
 
-use wasmhaiku_glue::fileparts::{FilePart, FileParts};
+use wasmhaiku_glue::{
+	context::RequestContext,
+	fileparts::{FilePart, FileParts},
+};
 
-use initial::Initial;
+use initial::{Args, Command, Connector, Initial};
+use jobs::{DeadLetter, Job, JobStatus};
+use pool::{Pool, PoolMetrics};
+use tasks::TASKS;
+use wasm::encode_headers;
 
 lazy_static! {
 	static ref INIT: Initial = Initial::new();
+	static ref ROUTER: Mutex<Router> = Mutex::new(connector_router(&INIT.connector()).unwrap());
+	static ref RELOADING: Mutex<()> = Mutex::new(());
+}
+
+fn request_context(
+	route: &str,
+	method: &Method,
+	uri: &Uri,
+	paths: HashMap<String, String>,
+	remote_addr: &SocketAddr,
+	headers: &HeaderMap,
+) -> String {
+	// keep the caller's request id if it sent one
+	let request_id = match headers.get("x-request-id").and_then(|v| v.to_str().ok()) {
+		Some(id) => id.to_string(),
+		None => Uuid::new_v4().to_string(),
+	};
+	RequestContext {
+		method: method.to_string(),
+		route: route.to_string(),
+		uri: uri.to_string(),
+		paths,
+		remote_addr: remote_addr.to_string(),
+		request_id,
+	}
+	.to_string()
 }
 
 fn settle_resp(
@@ -60,12 +122,64 @@ fn settle_resp(
 	));
 }
 
+fn settle_job_resp(
+	job_id: String,
+	ret_headers: String,
+	ret_body: Vec<u8>,
+) -> Result<(StatusCode, HeaderMap, Vec<u8>), (StatusCode, Vec<u8>)> {
+	let (status, mut headers, body) = settle_resp(200, ret_headers, ret_body)?;
+	if let Ok(header_value) = HeaderValue::from_str(&job_id) {
+		headers.insert(HeaderName::from_static("x-haiku-job-id"), header_value);
+	}
+	Ok((status, headers, body))
+}
+
+// Guests run on the blocking pool, so the runtime workers keep serving
+// while they wait on outbound calls
+async fn execute(
+	pool: Arc<Pool>,
+	job: Job,
+) -> Result<(Job, (u16, String, Vec<u8>)), (StatusCode, Vec<u8>)> {
+	let ret = tokio::task::spawn_blocking(move || {
+		let wasm = match pool.checkout() {
+			Ok(wasm) => wasm,
+			Err(e) => return Err((StatusCode::SERVICE_UNAVAILABLE, e.into_bytes())),
+		};
+		match wasm.execute(&job) {
+			Ok(ret) => Ok((job, ret)),
+			Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.into_bytes())),
+		}
+	})
+	.await;
+	match ret {
+		Ok(ret) => ret,
+		Err(e) => Err((
+			StatusCode::INTERNAL_SERVER_ERROR,
+			format!("{:?}", e).into_bytes(),
+		)),
+	}
+}
+
 fn handler(
+	pool: Arc<Pool>,
+	route: String,
 	func_name: String,
 	async_func_name: Option<String>,
-) -> impl Handler<(HeaderMap, Query<HashMap<String, String>>, Bytes)> {
+) -> impl Handler<(
+	HeaderMap,
+	Method,
+	Uri,
+	ConnectInfo<SocketAddr>,
+	Query<HashMap<String, String>>,
+	Path<HashMap<String, String>>,
+	Bytes,
+)> {
 	return |headers: HeaderMap,
+	        method: Method,
+	        uri: Uri,
+	        ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
 	        Query(queries): Query<HashMap<String, String>>,
+	        Path(paths): Path<HashMap<String, String>>,
 	        bytes: Bytes|
 	 -> Pin<
 		Box<
@@ -74,49 +188,56 @@ fn handler(
 		>,
 	> {
 		return Box::pin(async move {
-			let body = bytes.to_vec();
-			let headers = format!("{:?}", headers);
-			let queries = serde_json::to_string(&queries).unwrap();
-			match INIT.wasm.execute(
-				func_name.as_str(),
-				headers.as_str(),
-				queries.as_str(),
-				&body,
-			) {
-				Ok((ret_status, ret_headers, ret_body)) => {
+			let job = Job {
+				func_name,
+				headers: encode_headers(&headers),
+				queries: serde_json::to_string(&queries).unwrap(),
+				context: request_context(&route, &method, &uri, paths, &remote_addr, &headers),
+				body: bytes.to_vec(),
+				fileparts: None,
+			};
+			match execute(pool, job).await {
+				Ok((mut job, (ret_status, ret_headers, ret_body))) => {
 					if async_func_name.is_some() && ret_status == 100 {
-						tokio::spawn(async move {
-							let _ = INIT.wasm.execute(
-								async_func_name.unwrap().as_str(),
-								headers.as_str(),
-								queries.as_str(),
-								&body,
-							);
-						});
-						// return 200 if the async func is called
-						settle_resp(200, ret_headers, ret_body)
+						// the async func is queued with the same request
+						job.func_name = async_func_name.unwrap();
+						match INIT.jobs.enqueue(job) {
+							// return 200 if the async func is queued
+							Ok(job_id) => settle_job_resp(job_id, ret_headers, ret_body),
+							Err(e) => {
+								Err((StatusCode::INTERNAL_SERVER_ERROR, e.as_bytes().to_vec()))
+							}
+						}
 					} else {
 						settle_resp(ret_status, ret_headers, ret_body)
 					}
 				}
-				Err(e) => {
-					return Err((StatusCode::INTERNAL_SERVER_ERROR, e.as_bytes().to_vec()));
-				}
+				Err(e) => Err(e),
 			}
 		});
 	};
 }
 
 fn multipart_handler(
+	pool: Arc<Pool>,
+	route: String,
 	func_name: String,
 	async_func_name: Option<String>,
 ) -> impl Handler<(
 	HeaderMap,
+	Method,
+	Uri,
+	ConnectInfo<SocketAddr>,
 	Query<HashMap<String, String>>,
+	Path<HashMap<String, String>>,
 	ContentLengthLimit<Multipart, { 10 * 1024 * 1024 }>,
 )> {
 	return |headers: HeaderMap,
+	        method: Method,
+	        uri: Uri,
+	        ConnectInfo(remote_addr): ConnectInfo<SocketAddr>,
 	        Query(queries): Query<HashMap<String, String>>,
+	        Path(paths): Path<HashMap<String, String>>,
 	        ContentLengthLimit(mut multipart): ContentLengthLimit<
 		Multipart,
 		{
@@ -179,74 +300,270 @@ fn multipart_handler(
 				}
 			}
 
-			let body = serde_json::to_vec(&body).unwrap();
-			let fileparts = FileParts { inner: fileparts }.to_vec();
-			let headers = format!("{:?}", headers);
-			let queries = serde_json::to_string(&queries).unwrap();
-
-			match INIT.wasm.execute_fileparts(
-				func_name.as_str(),
-				headers.as_str(),
-				queries.as_str(),
-				&body,
-				&fileparts,
-			) {
-				Ok((ret_status, ret_headers, ret_body)) => {
+			let job = Job {
+				func_name,
+				headers: encode_headers(&headers),
+				queries: serde_json::to_string(&queries).unwrap(),
+				context: request_context(&route, &method, &uri, paths, &remote_addr, &headers),
+				body: serde_json::to_vec(&body).unwrap(),
+				fileparts: Some(FileParts { inner: fileparts }.to_vec()),
+			};
+			match execute(pool, job).await {
+				Ok((mut job, (ret_status, ret_headers, ret_body))) => {
 					if async_func_name.is_some() && ret_status == 100 {
-						tokio::spawn(async move {
-							let _ = INIT.wasm.execute_fileparts(
-								async_func_name.unwrap().as_str(),
-								headers.as_str(),
-								queries.as_str(),
-								&body,
-								&fileparts,
-							);
-						});
-						// return 200 if the async func is called
-						settle_resp(200, ret_headers, ret_body)
+						// the async func is queued with the same request
+						job.func_name = async_func_name.unwrap();
+						match INIT.jobs.enqueue(job) {
+							// return 200 if the async func is queued
+							Ok(job_id) => settle_job_resp(job_id, ret_headers, ret_body),
+							Err(e) => {
+								Err((StatusCode::INTERNAL_SERVER_ERROR, e.as_bytes().to_vec()))
+							}
+						}
 					} else {
 						settle_resp(ret_status, ret_headers, ret_body)
 					}
 				}
-				Err(e) => {
-					return Err((StatusCode::INTERNAL_SERVER_ERROR, e.as_bytes().to_vec()));
-				}
+				Err(e) => Err(e),
 			}
 		});
 	};
 }
 
-#[tokio::main]
-async fn main() {
-	INIT.wasm.init();
+async fn reload_tls_on_hangup(tls_config: RustlsConfig, cert: &str, key: &str) {
+	let mut hangup = signal(SignalKind::hangup()).unwrap();
+	while hangup.recv().await.is_some() {
+		if let Err(e) = tls_config.reload_from_pem_file(cert, key).await {
+			eprintln!("Failed to reload TLS certificate. {:?}", e);
+		}
+	}
+}
+
+async fn shutdown_on_signal(handle: Handle) -> Instant {
+	let mut interrupt = signal(SignalKind::interrupt()).unwrap();
+	let mut terminate = signal(SignalKind::terminate()).unwrap();
+	tokio::select! {
+		_ = interrupt.recv() => {}
+		_ = terminate.recv() => {}
+	}
+
+	let timeout = Duration::from_secs(INIT.args.shutdown_timeout);
+	handle.graceful_shutdown(Some(timeout));
+	Instant::now() + timeout
+}
+
+async fn pool_metrics() -> Json<PoolMetrics> {
+	Json(INIT.connector().pool.metrics())
+}
+
+async fn job_status(
+	Path(id): Path<String>,
+	headers: HeaderMap,
+) -> Result<Json<JobStatus>, StatusCode> {
+	if !authorized(&headers) {
+		return Err(StatusCode::UNAUTHORIZED);
+	}
+	match INIT.jobs.status(&id) {
+		Some(status) => Ok(Json(status)),
+		None => Err(StatusCode::NOT_FOUND),
+	}
+}
+
+async fn dead_letters(headers: HeaderMap) -> Result<Json<Vec<DeadLetter>>, StatusCode> {
+	if !authorized(&headers) {
+		return Err(StatusCode::UNAUTHORIZED);
+	}
+	Ok(Json(INIT.jobs.dead_letters()))
+}
+
+// Refuse the routes axum would panic on instead of building them
+fn connector_router(connector: &Connector) -> Result<Router, String> {
+	let routes = &connector.config.route;
+	if let Some((i, e)) = validate::check_routes(routes).into_iter().next() {
+		return Err(format!("Invalid route {}. {}", routes[i].path, e));
+	}
 
 	let mut app = Router::new();
 
-	for c in INIT.config.route.iter() {
+	for c in connector.config.route.iter() {
 		app = match c.content_type {
 			Some(route_config::ContentType::Multipart) => app.route(
 				c.path.as_str(),
 				routing::on(
 					MethodFilter::from_bits(c.method as u16).unwrap(),
-					multipart_handler(c.func_name.to_string(), c.async_func_name.clone()),
+					multipart_handler(
+						connector.pool.clone(),
+						c.path.to_string(),
+						c.func_name.to_string(),
+						c.async_func_name.clone(),
+					),
 				),
 			),
 			_ => app.route(
 				c.path.as_str(),
 				routing::on(
 					MethodFilter::from_bits(c.method as u16).unwrap(),
-					handler(c.func_name.to_string(), c.async_func_name.clone()),
+					handler(
+						connector.pool.clone(),
+						c.path.to_string(),
+						c.func_name.to_string(),
+						c.async_func_name.clone(),
+					),
 				),
 			),
 		}
 	}
 
-	let port = env::var("PORT").unwrap_or_else(|_| "9000".to_string());
-	let port = port.parse::<u16>().unwrap();
-	let addr = SocketAddr::from(([127, 0, 0, 1], port));
+	Ok(app)
+}
+
+// Route through the connector that is current when the request arrives
+async fn dispatch(req: Request<Body>) -> Response {
+	let mut router = ROUTER.lock().unwrap().clone();
+	router.call(req).await.unwrap_or_else(|e| match e {})
+}
+
+fn reload() -> Result<(), String> {
+	// the locks only guard a swap, a panic while holding them leaves nothing half done
+	let _reloading = RELOADING.lock().unwrap_or_else(PoisonError::into_inner);
+	let connector = Arc::new(Connector::load(&INIT.args, INIT.cassette.clone())?);
+	let router = connector_router(&connector)?;
+	INIT.swap(connector);
+	*ROUTER.lock().unwrap_or_else(PoisonError::into_inner) = router;
+	Ok(())
+}
+
+// Compare in constant time so the token can't be guessed byte by byte
+fn token_matches(given: &str, token: &str) -> bool {
+	given.len() == token.len()
+		&& given
+			.bytes()
+			.zip(token.bytes())
+			.fold(0, |acc, (a, b)| acc | (a ^ b))
+			== 0
+}
+
+// Whether the request carries the admin token, never without one
+fn authorized(headers: &HeaderMap) -> bool {
+	headers
+		.get(AUTHORIZATION)
+		.and_then(|v| v.to_str().ok())
+		.and_then(|v| v.strip_prefix("Bearer "))
+		.zip(INIT.args.admin_token.as_deref())
+		.map(|(given, token)| token_matches(given, token))
+		.unwrap_or(false)
+}
+
+async fn reload_connector(headers: HeaderMap) -> (StatusCode, String) {
+	if !authorized(&headers) {
+		return (StatusCode::UNAUTHORIZED, String::from("Unauthorized"));
+	}
+
+	match tokio::task::block_in_place(reload) {
+		Ok(_) => (StatusCode::OK, String::from("Reloaded")),
+		Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, inject::redact(&e)),
+	}
+}
+
+async fn watch_files() {
+	let modified = || {
+		[&INIT.args.wasm, &INIT.args.config]
+			.map(|p| fs::metadata(p).and_then(|m| m.modified()).ok())
+	};
+
+	let mut last = modified();
+	let mut interval = time::interval(Duration::from_secs(2));
+	loop {
+		interval.tick().await;
+		let current = modified();
+		if current != last {
+			last = current;
+			if let Err(e) = tokio::task::block_in_place(reload) {
+				eprintln!("Failed to reload the connector. {}", inject::redact(&e));
+			}
+		}
+	}
+}
+
+#[tokio::main]
+async fn main() {
+	// room for the host functions of every instance the pools and reloads create
+	if env::var_os("MAX_HOST_FUNC_LENGTH").is_none() {
+		env::set_var("MAX_HOST_FUNC_LENGTH", wasm::MAX_HOST_FUNCS.to_string());
+	}
+	let args = Args::parse();
+	match &args.command {
+		Some(Command::Validate) => {
+			process::exit(if validate::validate(&args) { 0 } else { 1 });
+		}
+		Some(Command::Invoke(invoke_args)) => {
+			let ok = tokio::task::block_in_place(|| invoke::invoke(&args, invoke_args));
+			// let the requests sent by send_async_request finish
+			let timeout = Duration::from_secs(args.shutdown_timeout);
+			if time::timeout(timeout, TASKS.wait()).await.is_err() {
+				eprintln!(
+					"Shutdown deadline reached with {} background task(s) still running",
+					TASKS.running()
+				);
+			}
+			process::exit(if ok { 0 } else { 1 });
+		}
+		None => (),
+	}
+
+	lazy_static::initialize(&ROUTER);
+
+	let mut app = Router::new().route("/_haiku/pool", routing::get(pool_metrics));
+	if INIT.args.admin_token.is_some() {
+		app = app
+			.route("/_haiku/reload", routing::post(reload_connector))
+			.route("/_haiku/jobs/:id", routing::get(job_status))
+			.route("/_haiku/dead-letters", routing::get(dead_letters));
+	}
+	let app = app.fallback(dispatch.into_service());
+
+	if INIT.args.watch {
+		tokio::spawn(watch_files());
+	}
+
+	let addr = SocketAddr::new(INIT.args.host, INIT.args.port);
+	let make_service = app.into_make_service_with_connect_info::<SocketAddr>();
+
+	let worker = tokio::spawn(INIT.jobs.run(|| INIT.connector().pool.clone()));
+
+	let handle = Handle::new();
+	let shutdown = tokio::spawn(shutdown_on_signal(handle.clone()));
+
+	match (&INIT.args.tls_cert, &INIT.args.tls_key) {
+		(Some(cert), Some(key)) => {
+			let tls_config = RustlsConfig::from_pem_file(cert, key).await.unwrap();
+			tokio::spawn(reload_tls_on_hangup(tls_config.clone(), cert, key));
+			axum_server::bind_rustls(addr, tls_config)
+				.handle(handle)
+				.serve(make_service)
+				.await
+				.unwrap();
+		}
+		_ => {
+			axum_server::bind(addr)
+				.handle(handle)
+				.serve(make_service)
+				.await
+				.unwrap();
+		}
+	}
+
+	// the server stops accepting connections once a signal is received,
+	// then drain the background work with what is left of the deadline
+	let deadline = shutdown.await.unwrap();
+	INIT.jobs.stop();
+	let _ = time::timeout_at(deadline, worker).await;
+	if time::timeout_at(deadline, TASKS.wait()).await.is_err() {
+		eprintln!(
+			"Shutdown deadline reached with {} background task(s) still running",
+			TASKS.running()
+		);
+	}
 
-	axum::Server::bind(&addr)
-		.serve(app.into_make_service())
-		.await
-		.unwrap();
+	INIT.connector().pool.shutdown();
 }
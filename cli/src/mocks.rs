@@ -0,0 +1,162 @@
+use reqwest::Method;
+use std::{fs, path::Path, time::Duration};
+use tokio::time;
+
+use wasmhaiku_glue::{error::Error, headers::Headers};
+
+use crate::route_config::{Config, MockConfig, MocksConfig};
+
+#[derive(Debug)]
+pub struct Mock {
+	url: String,
+	method: Option<Method>,
+	status: u16,
+	headers: String,
+	body: Vec<u8>,
+	latency: Duration,
+}
+
+// Canned upstream responses from the route config and the `--mocks` file,
+// requests matching none of them still go out
+#[derive(Debug, Default)]
+pub struct Mocks {
+	inner: Vec<Mock>,
+}
+
+// `*` matches any run of characters, everything else matches itself
+fn wildcard_matches(pattern: &str, s: &str) -> bool {
+	let (p, s) = (pattern.as_bytes(), s.as_bytes());
+	let (mut pi, mut si) = (0, 0);
+	// position of the last `*` and of the input it was tried against
+	let mut star: Option<(usize, usize)> = None;
+	while si < s.len() {
+		if pi < p.len() && p[pi] == b'*' {
+			star = Some((pi, si));
+			pi += 1;
+		} else if pi < p.len() && p[pi] == s[si] {
+			pi += 1;
+			si += 1;
+		} else if let Some((sp, ss)) = star {
+			pi = sp + 1;
+			si = ss + 1;
+			star = Some((sp, ss + 1));
+		} else {
+			return false;
+		}
+	}
+	p[pi..].iter().all(|c| *c == b'*')
+}
+
+impl Mock {
+	fn new(config: &MockConfig, dir: &Path) -> Result<Mock, String> {
+		let method = match &config.method {
+			Some(m) => match Method::from_bytes(m.to_ascii_uppercase().as_bytes()) {
+				Ok(m) => Some(m),
+				Err(_) => return Err(format!("Invalid method {} of the mock {}", m, config.url)),
+			},
+			None => None,
+		};
+
+		let body = match &config.body_file {
+			Some(f) => {
+				let path = dir.join(f);
+				match fs::read(&path) {
+					Ok(b) => b,
+					Err(e) => return Err(format!("Failed to read {}. {}", path.display(), e)),
+				}
+			}
+			None => vec![],
+		};
+
+		let mut headers = Headers::new();
+		for (k, v) in config.headers.iter() {
+			headers.append(&k.to_ascii_lowercase(), v.clone());
+		}
+
+		Ok(Mock {
+			url: config.url.clone(),
+			method,
+			status: config.status,
+			headers: headers.to_string(),
+			body,
+			latency: Duration::from_millis(config.latency_ms),
+		})
+	}
+
+	pub async fn respond(&self) -> Result<(u16, String, Vec<u8>), Error> {
+		if !self.latency.is_zero() {
+			time::sleep(self.latency).await;
+		}
+		Ok((self.status, self.headers.clone(), self.body.clone()))
+	}
+}
+
+impl Mocks {
+	pub fn new(
+		config_path: &str,
+		config: &Config,
+		mocks_path: Option<&String>,
+	) -> Result<Mocks, String> {
+		let dir = |path: &str| {
+			Path::new(path)
+				.parent()
+				.map(|p| p.to_path_buf())
+				.unwrap_or_default()
+		};
+
+		let mut inner = vec![];
+		for m in config.mock.iter() {
+			inner.push(Mock::new(m, &dir(config_path))?);
+		}
+		// mocks of the file come first, so they can override the route config's
+		if let Some(path) = mocks_path {
+			let mut from_file = vec![];
+			for m in MocksConfig::new(path)?.mock.iter() {
+				from_file.push(Mock::new(m, &dir(path))?);
+			}
+			from_file.extend(inner);
+			inner = from_file;
+		}
+
+		Ok(Mocks { inner })
+	}
+
+	// The first declared mock matching the request answers it
+	pub fn find(&self, method: &Method, url: &str) -> Option<&Mock> {
+		self.inner.iter().find(|m| {
+			m.method.as_ref().is_none_or(|mm| mm == method) && wildcard_matches(&m.url, url)
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn wildcards() {
+		assert!(wildcard_matches(
+			"https://api.example.com/*",
+			"https://api.example.com/v1/x"
+		));
+		assert!(wildcard_matches("*/v1/x", "https://api.example.com/v1/x"));
+		assert!(wildcard_matches(
+			"https://*.example.com/*/x",
+			"https://api.example.com/v1/x"
+		));
+		assert!(wildcard_matches("a*b*c", "aXbYbZc"));
+		assert!(wildcard_matches("a**c", "abc"));
+		assert!(wildcard_matches("*", ""));
+		assert!(wildcard_matches("abc*", "abc"));
+		assert!(!wildcard_matches("a*b*c", "aXbYbZ"));
+		assert!(!wildcard_matches("*/v2/*", "https://api.example.com/v1/x"));
+
+		// an empty pattern only matches an empty input
+		assert!(wildcard_matches("", ""));
+		assert!(!wildcard_matches("", "a"));
+
+		// a pattern longer than the input
+		assert!(!wildcard_matches("abcd", "abc"));
+		assert!(!wildcard_matches("abc*d", "abc"));
+	}
+}
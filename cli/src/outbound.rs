@@ -0,0 +1,355 @@
+use reqwest::{redirect, Certificate, Client, ClientBuilder, Identity, Proxy, Url};
+use std::{
+	collections::HashMap,
+	env, fs,
+	net::SocketAddr,
+	sync::{Arc, Mutex, Weak},
+	time::Duration,
+};
+
+use wasmhaiku_glue::{error::Error, options::RequestOptions};
+
+use crate::cassette::Cassette;
+use crate::egress::{Egress, Policy};
+use crate::mocks::Mocks;
+use crate::pool::Pool;
+use crate::route_config::OutboundConfig;
+
+const TIMEOUT: u64 = 120;
+const MAX_REDIRECTS: usize = 10;
+// Pinned clients come and go with the DNS answers, the cache is emptied past this
+const MAX_CLIENTS: usize = 256;
+
+// Egress policy name, max redirects, gzip and the host pinned to its checked address
+type ClientKey = (String, Option<usize>, bool, Option<(String, SocketAddr)>);
+
+// Entries are host names matching themselves and their subdomains, or `*`
+fn bypasses_proxy(no_proxy: &[String], host: &str) -> bool {
+	no_proxy.iter().any(|entry| {
+		let entry = entry.trim_start_matches('.');
+		entry == "*"
+			|| host.eq_ignore_ascii_case(entry)
+			|| host
+				.to_ascii_lowercase()
+				.ends_with(&format!(".{}", entry.to_ascii_lowercase()))
+	})
+}
+
+// Where the requests go through a proxy, the proxy environment variables
+// fill in the schemes the config leaves unset
+#[derive(Clone, Debug, Default)]
+struct ProxyRoutes {
+	http: Option<Url>,
+	https: Option<Url>,
+	no_proxy: Vec<String>,
+}
+
+impl ProxyRoutes {
+	fn new(config: &OutboundConfig) -> Result<ProxyRoutes, String> {
+		let parse = |url: Option<&String>| match url {
+			Some(u) => match Url::parse(u) {
+				Ok(u) => Ok(Some(u)),
+				Err(e) => Err(format!("Invalid proxy {}. {}", u, e)),
+			},
+			None => Ok(None),
+		};
+		// like reqwest, an invalid variable is ignored
+		let from_env = |upper: &str, lower: &str| {
+			env::var(upper)
+				.or_else(|_| env::var(lower))
+				.ok()
+				.and_then(|v| Url::parse(&v).ok())
+		};
+		let http = parse(config.http_proxy.as_ref().or(config.proxy.as_ref()))?
+			.or_else(|| from_env("HTTP_PROXY", "http_proxy"));
+		let https = parse(config.https_proxy.as_ref().or(config.proxy.as_ref()))?
+			.or_else(|| from_env("HTTPS_PROXY", "https_proxy"));
+
+		let no_proxy = match &config.no_proxy {
+			Some(hosts) => hosts.clone(),
+			None => env::var("NO_PROXY")
+				.or_else(|_| env::var("no_proxy"))
+				.map(|v| {
+					v.split(',')
+						.map(|h| h.trim().to_string())
+						.filter(|h| !h.is_empty())
+						.collect()
+				})
+				.unwrap_or_default(),
+		};
+
+		Ok(ProxyRoutes {
+			http,
+			https,
+			no_proxy,
+		})
+	}
+
+	fn route(&self, url: &Url) -> Option<Url> {
+		if bypasses_proxy(&self.no_proxy, url.host_str().unwrap_or_default()) {
+			return None;
+		}
+		match url.scheme() {
+			"http" => self.http.clone(),
+			"https" => self.https.clone(),
+			_ => None,
+		}
+	}
+}
+
+// Settings applied to every request a guest sends through the host functions
+#[derive(Debug)]
+pub struct Outbound {
+	pub redirect: Option<Url>,
+	pub egress: Egress,
+	pub mocks: Mocks,
+	pub cassette: Option<Arc<Cassette>>,
+	proxies: ProxyRoutes,
+	ca_certs: Vec<Certificate>,
+	identity: Option<Identity>,
+	accept_invalid_certs: bool,
+	// Shared by every instance so connections and TLS sessions are reused,
+	// keyed by the options that can only be set on a client
+	clients: Mutex<HashMap<ClientKey, Client>>,
+	// Pool running the completion callbacks of async requests
+	pool: Mutex<Weak<Pool>>,
+}
+
+impl Outbound {
+	pub fn new(
+		redirect: Option<Url>,
+		egress: Egress,
+		mocks: Mocks,
+		cassette: Option<Arc<Cassette>>,
+		config: &OutboundConfig,
+	) -> Result<Outbound, String> {
+		let mut ca_certs = vec![];
+		for path in config.ca_certs.iter() {
+			let pem = match fs::read(path) {
+				Ok(b) => b,
+				Err(e) => return Err(format!("Failed to read {}. {}", path, e)),
+			};
+			match Certificate::from_pem(&pem) {
+				Ok(c) => ca_certs.push(c),
+				Err(e) => return Err(format!("Invalid certificate {}. {:?}", path, e)),
+			}
+		}
+
+		let identity = match &config.client_identity {
+			Some(path) => {
+				let der = match fs::read(path) {
+					Ok(b) => b,
+					Err(e) => return Err(format!("Failed to read {}. {}", path, e)),
+				};
+				match Identity::from_pkcs12_der(&der, &config.client_identity_password) {
+					Ok(i) => Some(i),
+					Err(e) => return Err(format!("Invalid client identity {}. {:?}", path, e)),
+				}
+			}
+			None => None,
+		};
+
+		Ok(Outbound {
+			redirect,
+			egress,
+			mocks,
+			cassette,
+			proxies: ProxyRoutes::new(config)?,
+			ca_certs,
+			identity,
+			accept_invalid_certs: config.danger_accept_invalid_certs,
+			clients: Mutex::new(HashMap::new()),
+			pool: Mutex::new(Weak::new()),
+		})
+	}
+
+	pub fn attach(&self, pool: &Arc<Pool>) {
+		*self.pool.lock().unwrap() = Arc::downgrade(pool);
+	}
+
+	// None once the pool was replaced by a reload
+	pub fn pool(&self) -> Option<Arc<Pool>> {
+		self.pool.lock().unwrap().upgrade()
+	}
+
+	// Point the request at the redirect target, keeping its path and query
+	pub fn rewrite(&self, url: String) -> String {
+		let base = match &self.redirect {
+			Some(b) => b,
+			None => return url,
+		};
+		let mut target = match Url::parse(url.as_str()) {
+			Ok(u) => u,
+			Err(_) => return url,
+		};
+		if target.set_scheme(base.scheme()).is_err()
+			|| target.set_host(base.host_str()).is_err()
+			|| target.set_port(base.port()).is_err()
+		{
+			return url;
+		}
+		target.to_string()
+	}
+
+	// Follow the redirects the egress policy allows. The addresses of another
+	// host name can't be checked before reqwest connects to it, so with
+	// block_private that redirect is returned to the guest instead
+	fn redirect_policy(
+		&self,
+		policy: Arc<Policy>,
+		max: usize,
+		pinned: Option<String>,
+	) -> redirect::Policy {
+		let proxies = self.proxies.clone();
+		let rewriting = self.redirect.is_some();
+		redirect::Policy::custom(move |attempt| {
+			if attempt.previous().len() >= max {
+				let e = Error::Redirect(format!("Too many redirects, the limit is {}", max));
+				return attempt.error(e);
+			}
+			if let Err(e) = policy.check_url(attempt.url()) {
+				return attempt.error(e);
+			}
+			if policy.needs_lookup(attempt.url())
+				&& !rewriting
+				&& proxies.route(attempt.url()).is_none()
+				&& attempt.url().host_str() != pinned.as_deref()
+			{
+				return attempt.stop();
+			}
+			attempt.follow()
+		})
+	}
+
+	// The client for a request of the function, refused before any connection
+	// is made if its egress policy doesn't allow the destination
+	pub async fn client(
+		&self,
+		func_name: &str,
+		url: &str,
+		options: &RequestOptions,
+	) -> Result<Client, Error> {
+		let policy = self.egress.policy(func_name);
+		let target = match Url::parse(url) {
+			Ok(u) => u,
+			Err(e) => return Err(Error::InvalidUrl(format!("{} {}", e, url))),
+		};
+		policy.check_url(&target)?;
+		// a rewritten or proxied request doesn't connect to the host itself
+		let pin = if self.redirect.is_none() && self.proxies.route(&target).is_none() {
+			policy
+				.resolve(&target)
+				.await?
+				.map(|addr| (target.host_str().unwrap_or_default().to_string(), addr))
+		} else {
+			None
+		};
+
+		let key = (
+			policy.name.clone(),
+			options.max_redirects,
+			options.gzip,
+			pin.clone(),
+		);
+		let mut clients = self.clients.lock().unwrap();
+		if let Some(c) = clients.get(&key) {
+			return Ok(c.clone());
+		}
+
+		let redirects = match options.max_redirects {
+			Some(0) => redirect::Policy::none(),
+			max => self.redirect_policy(
+				policy,
+				max.unwrap_or(MAX_REDIRECTS),
+				pin.as_ref().map(|(host, _)| host.clone()),
+			),
+		};
+		let mut builder = ClientBuilder::new()
+			.timeout(Duration::from_secs(TIMEOUT))
+			.redirect(redirects)
+			.gzip(options.gzip)
+			.danger_accept_invalid_certs(self.accept_invalid_certs);
+		if let Some((host, addr)) = &pin {
+			builder = builder.resolve(host, *addr);
+		}
+		if self.proxies.http.is_some() || self.proxies.https.is_some() {
+			let proxies = self.proxies.clone();
+			builder = builder.proxy(Proxy::custom(move |url| proxies.route(url)));
+		} else {
+			builder = builder.no_proxy();
+		}
+		for cert in self.ca_certs.iter() {
+			builder = builder.add_root_certificate(cert.clone());
+		}
+		if let Some(identity) = &self.identity {
+			builder = builder.identity(identity.clone());
+		}
+		let c = match builder.build() {
+			Ok(c) => c,
+			Err(e) => return Err(Error::Other(format!("Failed to build the client. {:?}", e))),
+		};
+		if clients.len() >= MAX_CLIENTS {
+			clients.clear();
+		}
+		clients.insert(key, c.clone());
+		Ok(c)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tokio::{
+		io::{AsyncReadExt, AsyncWriteExt},
+		net::TcpListener,
+	};
+
+	use crate::route_config::Config;
+
+	// Answers every connection with a redirect to the location
+	async fn redirecting(location: &'static str) -> String {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		tokio::spawn(async move {
+			while let Ok((mut stream, _)) = listener.accept().await {
+				let mut buf = [0; 1024];
+				_ = stream.read(&mut buf).await;
+				let response = format!(
+					"HTTP/1.1 302 Found\r\nlocation: {}\r\ncontent-length: 0\r\n\r\n",
+					location
+				);
+				_ = stream.write_all(response.as_bytes()).await;
+			}
+		});
+		format!("http://{}/", addr)
+	}
+
+	fn outbound(egress: &str) -> Outbound {
+		let config = Config::parse(egress).unwrap();
+		let outbound_config = OutboundConfig {
+			no_proxy: Some(vec![String::from("*")]),
+			..Default::default()
+		};
+		Outbound::new(
+			None,
+			Egress::new(&config),
+			Mocks::default(),
+			None,
+			&outbound_config,
+		)
+		.unwrap()
+	}
+
+	#[tokio::test]
+	async fn redirects_are_checked() {
+		let url = redirecting("http://denied.example/").await;
+		let outbound = outbound("route = []\n[egress]\nallow_hosts = [\"127.0.0.1\"]");
+		let options = RequestOptions::default();
+		let c = outbound.client("f", &url, &options).await.unwrap();
+		let e = c.get(&url).send().await.unwrap_err();
+		assert!(matches!(
+			crate::wasm::request_error(e),
+			Error::Forbidden(m) if m.contains("denied.example")
+		));
+	}
+}
@@ -0,0 +1,447 @@
+use serde::Serialize;
+use std::{
+	mem,
+	ops::Deref,
+	sync::{Arc, Condvar, Mutex},
+	thread,
+	time::{Duration, Instant},
+};
+
+use crate::inject::Injected;
+use crate::kv::Kv;
+use crate::outbound::Outbound;
+use crate::route_config::PoolConfig;
+use crate::wasm::Wasm;
+
+// What the pool needs of its instances, besides creating them
+pub trait Instance: Send + Sync {
+	fn failed(&self) -> bool;
+	fn shutdown(&self);
+}
+
+impl Instance for Wasm {
+	fn failed(&self) -> bool {
+		Wasm::failed(self)
+	}
+
+	fn shutdown(&self) {
+		Wasm::shutdown(self)
+	}
+}
+
+type Create<W> = Box<dyn Fn() -> Result<W, String> + Send + Sync>;
+
+struct State<W> {
+	idle: Vec<W>,
+	size: usize,
+	waiting: usize,
+	closing: bool,
+	checkouts: u64,
+	waits: u64,
+	rejections: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PoolMetrics {
+	pub size: usize,
+	pub min_size: usize,
+	pub max_size: usize,
+	pub idle: usize,
+	pub in_use: usize,
+	pub waiting: usize,
+	pub max_queue: usize,
+	pub checkouts: u64,
+	pub waits: u64,
+	pub rejections: u64,
+}
+
+pub struct Pool<W: Instance = Wasm> {
+	config: PoolConfig,
+	create: Create<W>,
+	state: Mutex<State<W>>,
+	available: Condvar,
+}
+
+pub struct PooledWasm<'a, W: Instance = Wasm> {
+	pool: &'a Pool<W>,
+	wasm: Option<W>,
+}
+
+impl<W: Instance> Deref for PooledWasm<'_, W> {
+	type Target = W;
+
+	fn deref(&self) -> &W {
+		self.wasm.as_ref().unwrap()
+	}
+}
+
+impl<W: Instance> Drop for PooledWasm<'_, W> {
+	fn drop(&mut self) {
+		if let Some(wasm) = self.wasm.take() {
+			// a panic may have left the call half done
+			self.pool.checkin(wasm, thread::panicking());
+		}
+	}
+}
+
+// A slot reserved for an instance being created, freed unless it is filled
+struct Reserved<'a, W: Instance> {
+	pool: &'a Pool<W>,
+}
+
+impl<W: Instance> Drop for Reserved<'_, W> {
+	fn drop(&mut self) {
+		self.pool.release();
+	}
+}
+
+impl<W: Instance> Drop for Pool<W> {
+	fn drop(&mut self) {
+		self.shutdown();
+	}
+}
+
+impl Pool {
+	pub fn new(
+		wasm_path: String,
+		config: PoolConfig,
+		outbound: Outbound,
+		kv: Kv,
+		injected: Injected,
+	) -> Arc<Pool> {
+		let outbound = Arc::new(outbound);
+		let (kv, injected) = (Arc::new(kv), Arc::new(injected));
+		let create_outbound = outbound.clone();
+		let pool = Arc::new(Pool::with_create(
+			config,
+			Box::new(move || {
+				let wasm = Wasm::new(
+					wasm_path.clone(),
+					create_outbound.clone(),
+					kv.clone(),
+					injected.clone(),
+				)?;
+				wasm.init()?;
+				Ok(wasm)
+			}),
+		));
+		outbound.attach(&pool);
+		pool
+	}
+}
+
+impl<W: Instance> Pool<W> {
+	fn with_create(config: PoolConfig, create: Create<W>) -> Pool<W> {
+		Pool {
+			config,
+			create,
+			state: Mutex::new(State {
+				idle: vec![],
+				size: 0,
+				waiting: 0,
+				closing: false,
+				checkouts: 0,
+				waits: 0,
+				rejections: 0,
+			}),
+			available: Condvar::new(),
+		}
+	}
+
+	// Instantiate the minimum number of instances up front
+	pub fn init(&self) -> Result<(), String> {
+		let count = self.config.min_size.max(1).min(self.config.max_size);
+		let mut instances = Vec::with_capacity(count);
+		for _ in 0..count {
+			instances.push((self.create)()?);
+		}
+
+		let mut state = self.state.lock().unwrap();
+		state.size += count;
+		state.idle.extend(instances);
+		Ok(())
+	}
+
+	pub fn checkout(&self) -> Result<PooledWasm<'_, W>, String> {
+		let deadline = Instant::now() + Duration::from_millis(self.config.queue_timeout_ms);
+		let mut state = self.state.lock().unwrap();
+		state.checkouts += 1;
+
+		let mut queued = false;
+		loop {
+			if state.closing {
+				state.rejections += 1;
+				return Err(String::from("Wasm instance pool is shut down"));
+			}
+
+			if let Some(wasm) = state.idle.pop() {
+				return Ok(PooledWasm {
+					pool: self,
+					wasm: Some(wasm),
+				});
+			}
+
+			if state.size < self.config.max_size {
+				// reserve the slot and instantiate outside of the lock
+				state.size += 1;
+				drop(state);
+				let reserved = Reserved { pool: self };
+				let wasm = (self.create)()?;
+				mem::forget(reserved);
+				return Ok(PooledWasm {
+					pool: self,
+					wasm: Some(wasm),
+				});
+			}
+
+			if !queued {
+				if state.waiting >= self.config.max_queue {
+					state.rejections += 1;
+					return Err(String::from("Wasm instance pool queue is full"));
+				}
+				state.waits += 1;
+				queued = true;
+			}
+
+			let now = Instant::now();
+			if now >= deadline {
+				state.rejections += 1;
+				return Err(String::from("Timed out waiting for a Wasm instance"));
+			}
+
+			state.waiting += 1;
+			let (s, _) = self.available.wait_timeout(state, deadline - now).unwrap();
+			state = s;
+			state.waiting -= 1;
+		}
+	}
+
+	// A failed instance is dropped and its slot freed for a fresh one
+	fn checkin(&self, wasm: W, failed: bool) {
+		if failed || wasm.failed() {
+			self.release();
+			return;
+		}
+		let mut state = self.state.lock().unwrap();
+		if state.closing {
+			// the pool shut down while the instance was busy
+			drop(state);
+			wasm.shutdown();
+			self.release();
+			return;
+		}
+		state.idle.push(wasm);
+		drop(state);
+		self.available.notify_one();
+	}
+
+	fn release(&self) {
+		// runs while unwinding too, where a poisoned lock must not panic again
+		let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+		state.size -= 1;
+		drop(state);
+		self.available.notify_one();
+	}
+
+	// Call the exported shutdown function of every idle instance, and of the
+	// busy ones when they are checked in. No instance is checked out after this
+	pub fn shutdown(&self) {
+		let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+		state.closing = true;
+		let idle = mem::take(&mut state.idle);
+		let busy = state.size - idle.len();
+		state.size = busy;
+		drop(state);
+		self.available.notify_all();
+
+		if busy > 0 {
+			eprintln!(
+				"{} Wasm instance(s) still running are shut down when they return",
+				busy
+			);
+		}
+		for wasm in idle.iter() {
+			wasm.shutdown();
+		}
+	}
+
+	pub fn metrics(&self) -> PoolMetrics {
+		let state = self.state.lock().unwrap();
+		PoolMetrics {
+			size: state.size,
+			min_size: self.config.min_size,
+			max_size: self.config.max_size,
+			idle: state.idle.len(),
+			in_use: state.size - state.idle.len(),
+			waiting: state.waiting,
+			max_queue: self.config.max_queue,
+			checkouts: state.checkouts,
+			waits: state.waits,
+			rejections: state.rejections,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::route_config::Config;
+	use std::{
+		panic::{self, AssertUnwindSafe},
+		sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+	};
+
+	#[derive(Default)]
+	struct Fake {
+		failed: AtomicBool,
+		shut_down: Arc<AtomicUsize>,
+	}
+
+	impl Instance for Fake {
+		fn failed(&self) -> bool {
+			self.failed.load(Ordering::Relaxed)
+		}
+
+		fn shutdown(&self) {
+			self.shut_down.fetch_add(1, Ordering::Relaxed);
+		}
+	}
+
+	fn config(max_size: usize, max_queue: usize, queue_timeout_ms: u64) -> PoolConfig {
+		PoolConfig {
+			min_size: 1,
+			max_size,
+			max_queue,
+			queue_timeout_ms,
+		}
+	}
+
+	fn pool(config: PoolConfig) -> Pool<Fake> {
+		Pool::with_create(config, Box::new(|| Ok(Fake::default())))
+	}
+
+	#[test]
+	fn checkout() {
+		let pool = pool(config(2, 0, 0));
+		pool.init().unwrap();
+		assert_eq!(pool.metrics().size, 1);
+
+		let a = pool.checkout().unwrap();
+		// the second instance is created on demand, up to max_size
+		let b = pool.checkout().unwrap();
+		let m = pool.metrics();
+		assert_eq!((m.size, m.idle, m.in_use, m.checkouts), (2, 0, 2, 2));
+
+		drop(a);
+		b.failed.store(true, Ordering::Relaxed);
+		drop(b);
+		// the failed instance is dropped, the other one is reused
+		let m = pool.metrics();
+		assert_eq!((m.size, m.idle, m.in_use), (1, 1, 0));
+		let _c = pool.checkout().unwrap();
+		assert_eq!(pool.metrics().size, 1);
+	}
+
+	#[test]
+	fn max_queue() {
+		let pool = pool(config(1, 0, 1000));
+		let _held = pool.checkout().unwrap();
+		let e = pool.checkout().err().unwrap();
+		assert_eq!(e, "Wasm instance pool queue is full");
+		let m = pool.metrics();
+		assert_eq!((m.waits, m.rejections, m.waiting), (0, 1, 0));
+	}
+
+	#[test]
+	fn queue_timeout() {
+		let pool = pool(config(1, 1, 50));
+		let _held = pool.checkout().unwrap();
+		let started = Instant::now();
+		let e = pool.checkout().err().unwrap();
+		assert_eq!(e, "Timed out waiting for a Wasm instance");
+		assert!(started.elapsed() >= Duration::from_millis(50));
+		let m = pool.metrics();
+		assert_eq!((m.waits, m.rejections, m.waiting), (1, 1, 0));
+	}
+
+	#[test]
+	fn queued() {
+		let pool = pool(config(1, 1, 10000));
+		let held = pool.checkout().unwrap();
+		thread::scope(|s| {
+			let waiter = s.spawn(|| pool.checkout().map(|_| ()));
+			while pool.metrics().waiting == 0 {
+				thread::sleep(Duration::from_millis(1));
+			}
+			drop(held);
+			waiter.join().unwrap().unwrap();
+		});
+		let m = pool.metrics();
+		assert_eq!((m.size, m.waits, m.rejections), (1, 1, 0));
+	}
+
+	#[test]
+	fn create_failure() {
+		let calls = AtomicUsize::new(0);
+		let pool: Pool<Fake> = Pool::with_create(
+			config(1, 0, 0),
+			Box::new(move || match calls.fetch_add(1, Ordering::Relaxed) {
+				0 => Err(String::from("no")),
+				1 => panic!("no"),
+				_ => Ok(Fake::default()),
+			}),
+		);
+
+		// the reserved slot is freed whether the creation fails or panics
+		assert_eq!(pool.checkout().err().unwrap(), "no");
+		assert_eq!(pool.metrics().size, 0);
+		let panicked = panic::catch_unwind(AssertUnwindSafe(|| pool.checkout().map(|_| ())));
+		assert!(panicked.is_err());
+		assert_eq!(pool.metrics().size, 0);
+
+		let wasm = pool.checkout().unwrap();
+		assert_eq!(pool.metrics().size, 1);
+		// an instance checked in while panicking isn't reused
+		let panicked = panic::catch_unwind(AssertUnwindSafe(move || {
+			let _wasm = wasm;
+			panic!("no");
+		}));
+		assert!(panicked.is_err());
+		assert_eq!(pool.metrics().size, 0);
+	}
+
+	#[test]
+	fn shutdown() {
+		let shut_down = Arc::new(AtomicUsize::new(0));
+		let counter = shut_down.clone();
+		let pool: Pool<Fake> = Pool::with_create(
+			config(2, 0, 0),
+			Box::new(move || {
+				Ok(Fake {
+					shut_down: counter.clone(),
+					..Fake::default()
+				})
+			}),
+		);
+		let a = pool.checkout().unwrap();
+		drop(pool.checkout().unwrap());
+		pool.shutdown();
+		assert_eq!(shut_down.load(Ordering::Relaxed), 1);
+		assert!(pool.checkout().is_err());
+
+		// the busy instance is shut down once it returns
+		drop(a);
+		assert_eq!(shut_down.load(Ordering::Relaxed), 2);
+		assert_eq!(pool.metrics().size, 0);
+
+		// dropping the pool doesn't shut down anything twice
+		drop(pool);
+		assert_eq!(shut_down.load(Ordering::Relaxed), 2);
+	}
+
+	#[test]
+	fn max_size() {
+		assert!(Config::parse("route = []\n[pool]\nmax_size = 0\n").is_err());
+		assert!(Config::parse("route = []\n[pool]\nmax_size = 1\n").is_ok());
+	}
+}
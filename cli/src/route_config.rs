@@ -1,5 +1,5 @@
-use serde::{Deserialize, Serialize};
-use std::fs;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::{collections::HashMap, fs, thread};
 
 #[derive(Copy, Clone, Debug, Serialize, Deserialize)]
 pub enum Method {
@@ -40,20 +40,269 @@ pub struct Route {
 	pub path: String,
 	pub method: Method,
 	pub content_type: Option<ContentType>,
+	// Replaces the connector's egress policy for the route's functions
+	pub egress: Option<EgressConfig>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct PoolConfig {
+	pub min_size: usize,
+	#[serde(deserialize_with = "at_least_one")]
+	pub max_size: usize,
+	pub max_queue: usize,
+	pub queue_timeout_ms: u64,
+}
+
+// A pool of no instances would reject every request
+fn at_least_one<'de, D: Deserializer<'de>>(deserializer: D) -> Result<usize, D::Error> {
+	match usize::deserialize(deserializer)? {
+		0 => Err(de::Error::custom("max_size must be at least 1")),
+		n => Ok(n),
+	}
+}
+
+impl Default for PoolConfig {
+	fn default() -> PoolConfig {
+		PoolConfig {
+			min_size: 1,
+			max_size: thread::available_parallelism()
+				.map(|n| n.get())
+				.unwrap_or(1),
+			max_queue: 1024,
+			queue_timeout_ms: 30000,
+		}
+	}
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct QueueConfig {
+	pub path: String,
+	pub concurrency: usize,
+	pub max_attempts: u32,
+	pub backoff_base_ms: u64,
+	pub backoff_max_ms: u64,
+	pub poll_interval_ms: u64,
+	// Succeeded and dead jobs are deleted once this old, 0 keeps them
+	pub retention_ms: u64,
+}
+
+impl Default for QueueConfig {
+	fn default() -> QueueConfig {
+		QueueConfig {
+			path: String::from("haiku-jobs.db"),
+			concurrency: 4,
+			max_attempts: 5,
+			backoff_base_ms: 1000,
+			backoff_max_ms: 300000,
+			poll_interval_ms: 1000,
+			retention_ms: 7 * 24 * 3600 * 1000,
+		}
+	}
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct EgressConfig {
+	// Host names or wildcards like `*.example.com`, empty allows every host
+	pub allow_hosts: Vec<String>,
+	pub allow_schemes: Vec<String>,
+	// Empty allows every port
+	pub allow_ports: Vec<u16>,
+	// Refuse hosts resolving to loopback, private, link-local or reserved
+	// addresses. Through a proxy only literal addresses are checked since the
+	// proxy resolves the names, and a redirect to another host name is returned
+	// to the guest instead of followed
+	pub block_private: bool,
+}
+
+impl Default for EgressConfig {
+	fn default() -> EgressConfig {
+		EgressConfig {
+			allow_hosts: vec![],
+			allow_schemes: vec![String::from("http"), String::from("https")],
+			allow_ports: vec![],
+			block_private: false,
+		}
+	}
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct OutboundConfig {
+	// Proxy for both schemes unless overridden below, as an http, https or socks5 url.
+	// The HTTP_PROXY and HTTPS_PROXY variables are used for the unset ones
+	pub proxy: Option<String>,
+	pub http_proxy: Option<String>,
+	pub https_proxy: Option<String>,
+	// Hosts reached without the proxy, the NO_PROXY variable is used when unset
+	pub no_proxy: Option<Vec<String>>,
+	// PEM files of extra trusted root certificates
+	pub ca_certs: Vec<String>,
+	// PKCS#12 file with the client certificate and key for mutual TLS
+	pub client_identity: Option<String>,
+	pub client_identity_password: String,
+	// Only meant for local testing against self-signed upstreams
+	pub danger_accept_invalid_certs: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KvBackend {
+	Memory,
+	File,
+	Sqlite,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct KvConfig {
+	pub backend: KvBackend,
+	// Defaults to haiku-kv.json for the file backend and haiku-kv.db for SQLite
+	pub path: Option<String>,
+	// Keys of the connector, the name of the Wasm file when unset
+	pub namespace: Option<String>,
+}
+
+impl Default for KvConfig {
+	fn default() -> KvConfig {
+		KvConfig {
+			backend: KvBackend::Memory,
+			path: None,
+			namespace: None,
+		}
+	}
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VarSource {
+	#[default]
+	Env,
+	Dotenv,
+	File,
+}
+
+// A variable handed to the guest, either a literal value or read by key
+// from the process environment, the dotenv file or the secrets file
+#[derive(Clone, Debug, Deserialize)]
+pub struct VarConfig {
+	pub name: String,
+	pub value: Option<String>,
+	#[serde(default)]
+	pub from: VarSource,
+	// Defaults to the name
+	pub key: Option<String>,
+	// Secrets are only readable with get_secret unless this is set
+	#[serde(default)]
+	pub wasi_env: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct InjectConfig {
+	// KEY=VALUE lines
+	pub dotenv: Option<String>,
+	// TOML table of names to values
+	pub secrets_file: Option<String>,
+	pub env: Vec<VarConfig>,
+	pub secret: Vec<VarConfig>,
+}
+
+// A host directory the guest sees at the guest path
+#[derive(Clone, Debug, Deserialize)]
+pub struct PreopenConfig {
+	pub guest: String,
+	pub host: String,
+	// Refused, WasmEdge 0.7 can only preopen directories read-write
+	#[serde(default)]
+	pub readonly: bool,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct WasiConfig {
+	// Program args after the name of the Wasm file
+	pub args: Vec<String>,
+	pub preopen: Vec<PreopenConfig>,
+}
+
+fn default_mock_status() -> u16 {
+	200
+}
+
+// A canned upstream response served instead of calling out
+#[derive(Clone, Debug, Deserialize)]
+pub struct MockConfig {
+	// `*` matches any run of characters
+	pub url: String,
+	// Any method when unset
+	pub method: Option<String>,
+	#[serde(default = "default_mock_status")]
+	pub status: u16,
+	#[serde(default)]
+	pub headers: HashMap<String, String>,
+	// Relative to the file declaring the mock
+	pub body_file: Option<String>,
+	#[serde(default)]
+	pub latency_ms: u64,
+}
+
+// Layout of the file passed with `--mocks`
+#[derive(Debug, Default, Deserialize)]
+pub struct MocksConfig {
+	#[serde(default)]
+	pub mock: Vec<MockConfig>,
+}
+
+impl MocksConfig {
+	pub fn new(filepath: &str) -> Result<MocksConfig, String> {
+		let raw = match fs::read_to_string(filepath) {
+			Ok(s) => s,
+			Err(e) => return Err(format!("Failed to read {}. {}", filepath, e)),
+		};
+		match toml::from_str(raw.as_str()) {
+			Ok(c) => Ok(c),
+			Err(e) => Err(format!("Invalid mocks {}. {}", filepath, e)),
+		}
+	}
 }
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
 	pub route: Vec<Route>,
+	#[serde(default)]
+	pub pool: PoolConfig,
+	#[serde(default)]
+	pub queue: QueueConfig,
+	#[serde(default)]
+	pub egress: EgressConfig,
+	#[serde(default)]
+	pub outbound: OutboundConfig,
+	#[serde(default)]
+	pub mock: Vec<MockConfig>,
+	#[serde(default)]
+	pub kv: KvConfig,
+	#[serde(default)]
+	pub inject: InjectConfig,
+	#[serde(default)]
+	pub wasi: WasiConfig,
 }
 
 impl Config {
-	pub fn new(filepath: String) -> Config {
-		toml::from_str(
-			String::from_utf8(fs::read(filepath).unwrap())
-				.unwrap()
-				.as_str(),
-		)
-		.unwrap()
+	pub fn new(filepath: String) -> Result<Config, String> {
+		let raw = match fs::read_to_string(&filepath) {
+			Ok(s) => s,
+			Err(e) => return Err(format!("Failed to read {}. {}", filepath, e)),
+		};
+		match Config::parse(raw.as_str()) {
+			Ok(c) => Ok(c),
+			Err(e) => Err(format!("Invalid config {}. {}", filepath, e)),
+		}
+	}
+
+	pub fn parse(raw: &str) -> Result<Config, toml::de::Error> {
+		toml::from_str(raw)
 	}
 }
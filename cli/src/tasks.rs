@@ -0,0 +1,61 @@
+use lazy_static::lazy_static;
+use std::{
+	future::Future,
+	sync::atomic::{AtomicUsize, Ordering},
+};
+use tokio::sync::Notify;
+
+lazy_static! {
+	// Background work which has to be drained before the process exits
+	pub static ref TASKS: Tasks = Tasks::new();
+}
+
+pub struct Tasks {
+	running: AtomicUsize,
+	idle: Notify,
+}
+
+struct Running(&'static Tasks);
+
+impl Drop for Running {
+	fn drop(&mut self) {
+		if self.0.running.fetch_sub(1, Ordering::SeqCst) == 1 {
+			self.0.idle.notify_waiters();
+		}
+	}
+}
+
+impl Tasks {
+	fn new() -> Tasks {
+		Tasks {
+			running: AtomicUsize::new(0),
+			idle: Notify::new(),
+		}
+	}
+
+	pub fn spawn<F>(&'static self, future: F)
+	where
+		F: Future<Output = ()> + Send + 'static,
+	{
+		self.running.fetch_add(1, Ordering::SeqCst);
+		let running = Running(self);
+		tokio::spawn(async move {
+			let _running = running;
+			future.await;
+		});
+	}
+
+	pub fn running(&self) -> usize {
+		self.running.load(Ordering::SeqCst)
+	}
+
+	pub async fn wait(&self) {
+		loop {
+			let idle = self.idle.notified();
+			if self.running() == 0 {
+				return;
+			}
+			idle.await;
+		}
+	}
+}
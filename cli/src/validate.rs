@@ -0,0 +1,290 @@
+use std::{fs, path::Path};
+use wasmedge_sys::*;
+use wasmedge_types::{ExternalInstanceType, ValType};
+
+use crate::initial::Args;
+use crate::route_config::{self, Route};
+
+// Signature of the functions exported through wasmedge-bindgen, taking the
+// pointer and count of the parameters and returning a pointer to the results
+const BINDGEN_PARAMS: [ValType; 2] = [ValType::I32, ValType::I32];
+const BINDGEN_RETURNS: [ValType; 1] = [ValType::I32];
+
+struct Export {
+	name: String,
+	params: Vec<ValType>,
+	returns: Vec<ValType>,
+}
+
+// `file:line` of the nth `key = "value"`, or just the file if it isn't written that way
+fn location(file: &str, raw: &str, key: &str, value: &str, nth: usize) -> String {
+	let quoted = format!("\"{}\"", value);
+	match raw
+		.lines()
+		.enumerate()
+		.filter(|(_, l)| {
+			let l = l.trim_start();
+			l.starts_with(key) && l.contains(&quoted)
+		})
+		.nth(nth)
+	{
+		Some((i, _)) => format!("{}:{}", file, i + 1),
+		None => file.to_string(),
+	}
+}
+
+// Whether the router can't tell the two paths apart: captures at the same
+// position need the same name, and a catch-all overlaps everything after it
+fn paths_conflict(a: &str, b: &str) -> bool {
+	let (a, b): (Vec<&str>, Vec<&str>) = (a.split('/').collect(), b.split('/').collect());
+	for (sa, sb) in a.iter().zip(b.iter()) {
+		if sa.starts_with('*') || sb.starts_with('*') {
+			return true;
+		}
+		match (sa.strip_prefix(':'), sb.strip_prefix(':')) {
+			(Some(na), Some(nb)) if na != nb => return true,
+			(Some(_), Some(_)) => (),
+			// static segments take priority over captures
+			(Some(_), None) | (None, Some(_)) => return false,
+			(None, None) if sa != sb => return false,
+			(None, None) => (),
+		}
+	}
+	false
+}
+
+// Problems that would make building the router panic, with the index of the route
+pub fn check_routes(routes: &[Route]) -> Vec<(usize, String)> {
+	let mut problems = vec![];
+	for (i, route) in routes.iter().enumerate() {
+		if !route.path.starts_with('/') {
+			problems.push((i, String::from("the path must start with `/`")));
+			continue;
+		}
+		// one problem per route is enough
+		for other in routes[..i].iter() {
+			if other.path == route.path && (other.method as u16) == (route.method as u16) {
+				let method = format!("{:?}", route.method).to_uppercase();
+				problems.push((i, format!("{} {} is already routed", method, route.path)));
+				break;
+			}
+			if other.path != route.path && paths_conflict(&other.path, &route.path) {
+				problems.push((i, format!("the path conflicts with {}", other.path)));
+				break;
+			}
+		}
+	}
+	problems
+}
+
+fn load_exports(filepath: &str) -> Result<Vec<Export>, String> {
+	let mut config = match Config::create() {
+		Ok(c) => c,
+		Err(e) => {
+			return Err(format!(
+				"{}: failed to create the config. {:?}",
+				filepath, e
+			))
+		}
+	};
+	config.wasi(true);
+	let module = match Loader::create(Some(config)).and_then(|l| l.from_file(Path::new(filepath))) {
+		Ok(m) => m,
+		Err(e) => return Err(format!("{}: failed to load. {:?}", filepath, e)),
+	};
+	if let Err(e) = Validator::create(None).and_then(|v| v.validate(&module)) {
+		return Err(format!("{}: failed to validate. {:?}", filepath, e));
+	}
+
+	Ok(module
+		.exports()
+		.iter()
+		.filter_map(|export| match export.ty() {
+			Ok(ExternalInstanceType::Func(func_ty)) => Some(Export {
+				name: export.name().to_string(),
+				params: func_ty.args().map(|a| a.to_vec()).unwrap_or_default(),
+				returns: func_ty.returns().map(|r| r.to_vec()).unwrap_or_default(),
+			}),
+			_ => None,
+		})
+		.collect())
+}
+
+fn check_bindgen(exports: &[Export], func_name: &str) -> Result<(), String> {
+	match exports.iter().find(|e| e.name == func_name) {
+		Some(e) if e.params == BINDGEN_PARAMS && e.returns == BINDGEN_RETURNS => Ok(()),
+		Some(e) => Err(format!(
+			"`{}` has signature {:?} -> {:?}, expected a wasmedge-bindgen function",
+			func_name, e.params, e.returns
+		)),
+		None => Err(format!("`{}` is not exported by the Wasm file", func_name)),
+	}
+}
+
+// Refuse a Wasm file whose init or route functions can't be called
+pub fn check_exports(config: &route_config::Config, filepath: &str) -> Result<(), String> {
+	let exports = load_exports(filepath)?;
+	let mut funcs = vec!["init"];
+	for route in config.route.iter() {
+		funcs.push(&route.func_name);
+		if let Some(async_func_name) = &route.async_func_name {
+			funcs.push(async_func_name);
+		}
+	}
+	let problems: Vec<String> = funcs
+		.into_iter()
+		.filter_map(|f| check_bindgen(&exports, f).err())
+		.collect();
+	if problems.is_empty() {
+		Ok(())
+	} else {
+		Err(format!("{}: {}", filepath, problems.join(", ")))
+	}
+}
+
+// Print every problem found and return whether the connector is valid
+pub fn validate(args: &Args) -> bool {
+	let mut problems = vec![];
+
+	let raw = match fs::read_to_string(&args.config) {
+		Ok(s) => s,
+		Err(e) => {
+			eprintln!("{}: failed to read. {}", args.config, e);
+			return false;
+		}
+	};
+	let config = match route_config::Config::parse(raw.as_str()) {
+		Ok(c) => Some(c),
+		Err(e) => {
+			let (line, col) = e.line_col().unwrap_or_default();
+			problems.push(format!("{}:{}:{}: {}", args.config, line + 1, col + 1, e));
+			None
+		}
+	};
+
+	let exports = match load_exports(&args.wasm) {
+		Ok(exports) => {
+			println!("Exports of {}:", args.wasm);
+			for e in exports.iter() {
+				println!("  {} {:?} -> {:?}", e.name, e.params, e.returns);
+			}
+			Some(exports)
+		}
+		Err(e) => {
+			problems.push(e);
+			None
+		}
+	};
+
+	if let Some(config) = &config {
+		for (i, e) in check_routes(&config.route) {
+			let path = &config.route[i].path;
+			// earlier routes with the same path come first in the file
+			let nth = config.route[..i].iter().filter(|r| &r.path == path).count();
+			problems.push(format!(
+				"{}: route {}: {}",
+				location(&args.config, raw.as_str(), "path", path, nth),
+				path,
+				e
+			));
+		}
+	}
+
+	if let Some(exports) = &exports {
+		match exports.iter().find(|e| e.name == "allocate") {
+			Some(e) if e.params == [ValType::I32] && e.returns == [ValType::I32] => (),
+			Some(e) => problems.push(format!(
+				"{}: `allocate` has signature {:?} -> {:?}, expected [I32] -> [I32]",
+				args.wasm, e.params, e.returns
+			)),
+			None => problems.push(format!("{}: `allocate` is not exported", args.wasm)),
+		}
+		if let Err(e) = check_bindgen(exports, "init") {
+			problems.push(format!("{}: {}", args.wasm, e));
+		}
+		// shutdown is optional, but has to be callable if present
+		if exports.iter().any(|e| e.name == "shutdown") {
+			if let Err(e) = check_bindgen(exports, "shutdown") {
+				problems.push(format!("{}: {}", args.wasm, e));
+			}
+		}
+
+		if let Some(config) = &config {
+			for route in config.route.iter() {
+				let mut funcs = vec![("func_name", &route.func_name)];
+				if let Some(async_func_name) = &route.async_func_name {
+					funcs.push(("async_func_name", async_func_name));
+				}
+				for (key, func_name) in funcs {
+					if let Err(e) = check_bindgen(exports, func_name) {
+						problems.push(format!(
+							"{}: route {}: {}",
+							location(&args.config, raw.as_str(), key, func_name, 0),
+							route.path,
+							e
+						));
+					}
+				}
+			}
+		}
+	}
+
+	for p in problems.iter() {
+		eprintln!("{}", p);
+	}
+	if problems.is_empty() {
+		println!(
+			"{} routes are valid",
+			config.map(|c| c.route.len()).unwrap_or(0)
+		);
+	}
+	problems.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn routes(paths: &[(&str, &str)]) -> Vec<Route> {
+		let raw: String = paths
+			.iter()
+			.map(|(method, path)| {
+				format!(
+					"[[route]]\nfunc_name = \"f\"\npath = \"{}\"\nmethod = \"{}\"\n",
+					path, method
+				)
+			})
+			.collect();
+		route_config::Config::parse(&raw).unwrap().route
+	}
+
+	#[test]
+	fn route_paths() {
+		let ok = routes(&[
+			("GET", "/a/:x"),
+			("POST", "/a/:x"),
+			("GET", "/a/:x/b"),
+			("GET", "/a/new"),
+			("GET", "/b/*rest"),
+		]);
+		assert!(check_routes(&ok).is_empty());
+
+		let bad = routes(&[
+			("GET", "a"),
+			("GET", "/a/:x"),
+			("GET", "/a/:y"),
+			("GET", "/a/:x"),
+			("GET", "/b/c"),
+			("GET", "/b/*rest"),
+		]);
+		let problems: Vec<usize> = check_routes(&bad).into_iter().map(|(i, _)| i).collect();
+		assert_eq!(problems, vec![0, 2, 3, 5]);
+	}
+
+	#[test]
+	fn locations() {
+		let raw = "[[route]]\npath = \"/a\"\n[[route]]\npath = \"/a\"\n";
+		assert_eq!(location("c.toml", raw, "path", "/a", 1), "c.toml:4");
+		assert_eq!(location("c.toml", raw, "func_name", "f", 0), "c.toml");
+	}
+}
@@ -1,207 +1,606 @@
 use hyper::HeaderMap;
 use reqwest::{
-	blocking::{multipart, ClientBuilder},
 	header::{HeaderName, HeaderValue},
-	Method,
+	multipart, Method, Response, Url,
 };
-use serde_json::value::Value;
+use serde_json::{value::Value, Map};
 use std::{
 	borrow::BorrowMut,
+	collections::HashMap,
 	convert::From,
+	env,
+	future::Future,
 	path::Path,
 	str::FromStr,
-	sync::{Arc, Mutex},
+	sync::{
+		atomic::{AtomicBool, AtomicUsize, Ordering},
+		Arc, Mutex,
+	},
 	time::Duration,
 };
+use tokio::{
+	runtime::Handle,
+	task::JoinHandle,
+	time::{self, Instant},
+};
 use wasmedge_bindgen_host::{Bindgen, Param};
 use wasmedge_sys::*;
 use wasmedge_types::ValType;
 
-use wasmhaiku_glue::{fileparts::FileParts, RequestMethod};
+use wasmhaiku_glue::{
+	batch::{Batch, BatchResult, BatchResults},
+	callback::{CallbackContext, CALLBACK_HEADER, CORRELATION_HEADER},
+	error::Error,
+	fileparts::FileParts,
+	headers::Headers,
+	options::RequestOptions,
+	RequestMethod, METHOD_HEADER,
+};
 
-const TIMEOUT: u64 = 120;
+use crate::cassette::RecordedRequest;
+use crate::inject::{redact, Injected};
+use crate::jobs::Job;
+use crate::kv::Kv;
+use crate::outbound::Outbound;
+use crate::pool::Pool;
+use crate::tasks::TASKS;
 
 enum WasmEdgeResultCode {
 	// SUCCESS = 0, // Success result is always returned with body, so this value is not needed
+	// Only used when the result can not be written back, other errors are returned to the guest
 	TERMINATE = 1,
-	FAIL = 2,
+}
+
+// wasmedge-sys keeps every host function for the life of the process and
+// refuses new ones past MAX_HOST_FUNC_LENGTH, so the instances created by the
+// pools and the reloads share that budget
+pub const MAX_HOST_FUNCS: usize = 65536;
+static HOST_FUNCS_USED: AtomicUsize = AtomicUsize::new(0);
+
+fn reserve_host_funcs(count: usize) -> Result<(), String> {
+	// the same default as wasmedge-sys when unset
+	let limit = env::var("MAX_HOST_FUNC_LENGTH")
+		.ok()
+		.and_then(|v| v.parse().ok())
+		.unwrap_or(500);
+	match HOST_FUNCS_USED.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |used| {
+		(used + count <= limit).then_some(used + count)
+	}) {
+		Ok(_) => Ok(()),
+		Err(used) => Err(format!(
+			"No Wasm instance can be created anymore, {} of the {} host functions of MAX_HOST_FUNC_LENGTH are used",
+			used, limit
+		)),
+	}
+}
+
+fn wasm_memory(vm: &Vm) -> Result<Memory, u8> {
+	match vm.active_module().and_then(|m| m.get_memory("memory")) {
+		Ok(memory) => Ok(memory),
+		Err(_) => Err(WasmEdgeResultCode::TERMINATE as u8),
+	}
+}
+
+pub fn encode_headers(headers: &HeaderMap) -> String {
+	let mut h = Headers::new();
+	for (k, v) in headers.iter() {
+		h.append(
+			k.as_str(),
+			String::from_utf8_lossy(v.as_bytes()).into_owned(),
+		);
+	}
+	h.to_string()
+}
+
+// Status, headers and body of an outbound request
+type Exchange = Result<(u16, String, Vec<u8>), Error>;
+
+// Sort a transport failure into the error kinds the guest can act on
+pub fn request_error(e: reqwest::Error) -> Error {
+	// errors of the redirect policy come back as they were raised
+	let raised = std::error::Error::source(&e).and_then(|s| s.downcast_ref::<Error>());
+	if let Some(raised) = raised {
+		return raised.clone();
+	}
+	let message = format!("{:?}", e);
+	if e.is_timeout() {
+		Error::Timeout(message)
+	} else if e.is_builder() {
+		Error::InvalidUrl(message)
+	} else if e.is_redirect() {
+		Error::Redirect(message)
+	} else if e.is_connect() && message.contains("dns error") {
+		Error::Dns(message)
+	} else if e.is_connect() && (message.contains("certificate") || message.contains("Tls")) {
+		Error::Tls(message)
+	} else if e.is_connect() {
+		Error::Connect(message)
+	} else if e.is_body() || e.is_decode() {
+		Error::Body(message)
+	} else {
+		Error::Other(message)
+	}
+}
+
+// Suspend the guest until an outbound call completes on the runtime. Guests run
+// on blocking threads, so the runtime workers keep serving meanwhile
+fn block_on<F: Future>(fut: F) -> F::Output {
+	let handle = Handle::current();
+	tokio::task::block_in_place(move || handle.block_on(fut))
+}
+
+struct OutboundRequest {
+	url: String,
+	method: Method,
+	headers: HeaderMap,
+	body: Vec<u8>,
+	options: RequestOptions,
+	// exported function and correlation data for the completion callback
+	callback: Option<(String, String)>,
 }
 
 pub struct Wasm {
 	bg: Arc<Mutex<Bindgen>>,
+	outbound: Arc<Outbound>,
+	// exported function being run, its egress policy applies to the outbound requests
+	running: Arc<Mutex<String>>,
+	// context of the function being run
+	context: Arc<Mutex<Context>>,
+	kv: Arc<Kv>,
+	injected: Arc<Injected>,
+	// a trapped call can leave the guest's memory inconsistent
+	failed: Arc<AtomicBool>,
+	pending: Arc<Mutex<Pending>>,
+}
+
+// Read with get_request_context by handlers and get_callback_context by callbacks
+enum Context {
+	Request(String),
+	Callback(String),
+}
+
+#[derive(Clone, Copy)]
+enum KvOp {
+	Get,
+	Set,
+	Delete,
+	ListPrefix,
+}
+
+// What a request begun by the guest ends with
+enum Outcome {
+	Exchange(Exchange),
+	// encoded BatchResults
+	Batch(Vec<u8>),
+}
+
+// Kinds of result of poll_request besides the one of send_request, the same
+// as those of the blocking functions
+const POLL_RESULT_WITH_HEADERS: i32 = 1;
+const POLL_BATCH: i32 = 2;
+
+// Requests begun by the guest, running on the runtime until it polls them.
+// wasmedge-sys holds one lock of the process over every host call, so a guest
+// waiting inside one would stall the host calls of all the others
+#[derive(Default)]
+struct Pending {
+	last: i32,
+	calls: HashMap<i32, JoinHandle<Outcome>>,
+}
+
+impl Pending {
+	fn begin<F>(&mut self, fut: F) -> i32
+	where
+		F: Future<Output = Outcome> + Send + 'static,
+	{
+		// handles are positive, 0 is what poll_request returns meanwhile
+		self.last = self.last.checked_add(1).unwrap_or(1);
+		self.calls.insert(self.last, tokio::spawn(fut));
+		self.last
+	}
+
+	// None while the request is running
+	fn poll(&mut self, handle: i32) -> Option<Result<Outcome, Error>> {
+		match self.calls.get(&handle) {
+			Some(h) if !h.is_finished() => None,
+			Some(_) => {
+				let h = self.calls.remove(&handle)?;
+				Some(block_on(h).map_err(|e| Error::Other(format!("{:?}", e))))
+			}
+			None => Some(Err(Error::InvalidRequest(format!(
+				"Unknown request handle {}",
+				handle
+			)))),
+		}
+	}
+
+	// Requests left unpolled end with the call that began them
+	fn clear(&mut self) {
+		for (_, h) in self.calls.drain() {
+			h.abort();
+		}
+	}
 }
 
 impl Clone for Wasm {
 	fn clone(&self) -> Self {
 		Wasm {
 			bg: self.bg.clone(),
+			outbound: self.outbound.clone(),
+			running: self.running.clone(),
+			context: self.context.clone(),
+			kv: self.kv.clone(),
+			injected: self.injected.clone(),
+			failed: self.failed.clone(),
+			pending: self.pending.clone(),
 		}
 	}
 }
 
 impl Wasm {
-	pub fn new(filepath: String) -> Wasm {
-		let mut config = Config::create().unwrap();
+	pub fn new(
+		filepath: String,
+		outbound: Arc<Outbound>,
+		kv: Arc<Kv>,
+		injected: Arc<Injected>,
+	) -> Result<Wasm, String> {
+		let mut config = match Config::create() {
+			Ok(c) => c,
+			Err(e) => return Err(format!("Failed to create the config. {:?}", e)),
+		};
 		config.wasi(true);
 
-		let mut vm = Vm::create(Some(config), None).unwrap();
+		let mut vm = match Vm::create(Some(config), None) {
+			Ok(vm) => vm,
+			Err(e) => return Err(format!("Failed to create the VM. {:?}", e)),
+		};
 
 		// get default wasi module
-		let mut wasi_module = vm.wasi_module_mut().unwrap();
+		let mut wasi_module = match vm.wasi_module_mut() {
+			Ok(m) => m,
+			Err(e) => return Err(format!("Failed to get the WASI module. {:?}", e)),
+		};
 		// init the default wasi module
-		wasi_module.init_wasi(Some(vec![]), Some(vec![]), Some(vec![]));
+		wasi_module.init_wasi(
+			Some(injected.args.iter().map(|a| a.as_str()).collect()),
+			Some(injected.env.iter().map(|e| e.as_str()).collect()),
+			Some(injected.preopens.iter().map(|p| p.as_str()).collect()),
+		);
 
 		let wasm_path = Path::new(&filepath);
-		let _ = vm.load_wasm_from_file(wasm_path);
-		let _ = vm.validate();
+		if let Err(e) = vm.load_wasm_from_file(wasm_path) {
+			return Err(format!("Failed to load {}. {:?}", filepath, e));
+		}
+		if let Err(e) = vm.validate() {
+			return Err(format!("Failed to validate {}. {:?}", filepath, e));
+		}
 
 		let this = Wasm {
 			bg: Arc::new(Mutex::new(Bindgen::new(vm))),
+			outbound,
+			running: Arc::new(Mutex::new(String::new())),
+			context: Arc::new(Mutex::new(Context::Request(String::new()))),
+			kv,
+			injected,
+			failed: Arc::new(AtomicBool::new(false)),
+			pending: Arc::new(Mutex::new(Pending::default())),
 		};
 
+		let i32s = |n: usize| vec![ValType::I32; n];
+		let host_funcs: Vec<(&str, Vec<ValType>, Vec<ValType>, BoxedFn)> = vec![
+			(
+				"send_request",
+				i32s(7),
+				i32s(1),
+				Box::new(this.clone().send_request(false)),
+			),
+			(
+				"send_request_with_headers",
+				i32s(7),
+				i32s(1),
+				Box::new(this.clone().send_request(true)),
+			),
+			(
+				"send_async_request",
+				i32s(7),
+				vec![],
+				Box::new(this.clone().send_async_request()),
+			),
+			(
+				"send_fileparts_request",
+				i32s(9),
+				i32s(1),
+				Box::new(this.clone().send_fileparts_request(false)),
+			),
+			(
+				"send_fileparts_request_with_headers",
+				i32s(9),
+				i32s(1),
+				Box::new(this.clone().send_fileparts_request(true)),
+			),
+			(
+				"send_async_fileparts_request",
+				i32s(9),
+				vec![],
+				Box::new(this.clone().send_async_fileparts_request()),
+			),
+			(
+				"send_batch_request",
+				i32s(3),
+				i32s(1),
+				Box::new(this.clone().send_batch_request()),
+			),
+			(
+				"begin_request",
+				i32s(7),
+				i32s(1),
+				Box::new(this.clone().begin_request()),
+			),
+			(
+				"begin_fileparts_request",
+				i32s(9),
+				i32s(1),
+				Box::new(this.clone().begin_fileparts_request()),
+			),
+			(
+				"begin_batch_request",
+				i32s(3),
+				i32s(1),
+				Box::new(this.clone().begin_batch_request()),
+			),
+			(
+				"poll_request",
+				i32s(2),
+				i32s(1),
+				Box::new(this.clone().poll_request()),
+			),
+			// the key-value functions all take the key or prefix first
+			(
+				"kv_get",
+				i32s(2),
+				i32s(1),
+				Box::new(this.clone().kv_call(KvOp::Get)),
+			),
+			(
+				"kv_set",
+				[i32s(4), vec![ValType::I64]].concat(),
+				i32s(1),
+				Box::new(this.clone().kv_call(KvOp::Set)),
+			),
+			(
+				"kv_delete",
+				i32s(2),
+				i32s(1),
+				Box::new(this.clone().kv_call(KvOp::Delete)),
+			),
+			(
+				"kv_list_prefix",
+				i32s(2),
+				i32s(1),
+				Box::new(this.clone().kv_call(KvOp::ListPrefix)),
+			),
+			(
+				"get_secret",
+				i32s(2),
+				i32s(1),
+				Box::new(this.clone().get_secret()),
+			),
+			(
+				"get_request_context",
+				vec![],
+				i32s(1),
+				Box::new(this.clone().get_context(false)),
+			),
+			(
+				"get_callback_context",
+				vec![],
+				i32s(1),
+				Box::new(this.clone().get_context(true)),
+			),
+		];
+		reserve_host_funcs(host_funcs.len())?;
+
+		let mut imp_obj = match ImportModule::create("haiku-connector") {
+			Ok(m) => m,
+			Err(e) => return Err(format!("Failed to create the host module. {:?}", e)),
+		};
+		for (name, params, returns, real_fn) in host_funcs {
+			match FuncType::create(params, returns).and_then(|ty| Function::create(&ty, real_fn, 0))
+			{
+				Ok(func) => imp_obj.add_func(name, func),
+				Err(e) => {
+					return Err(format!(
+						"Failed to create the host function {}. {:?}",
+						name, e
+					))
+				}
+			}
+		}
+
 		{
-			let mut mut_guard = this.bg.lock().unwrap();
-			let vm = mut_guard.borrow_mut().vm();
-
-			// Register the host function 'send_request'
-			let mut imp_obj = ImportModule::create("haiku-connector").unwrap();
-
-			let func_ty = FuncType::create(vec![ValType::I32; 7], vec![ValType::I32; 1])
-				.expect("fail to create a FuncType");
-			let boxed_fn = Box::new(this.clone().send_request());
-			let func = Function::create(&func_ty, boxed_fn, 0)
-				.expect("fail to create a Function instance");
-			imp_obj.add_func("send_request", func);
-
-			// Register the host function 'send_async_request'
-			let func_ty =
-				FuncType::create(vec![ValType::I32; 7], vec![]).expect("fail to create a FuncType");
-			let boxed_fn = Box::new(this.clone().send_async_request());
-			let func = Function::create(&func_ty, boxed_fn, 0)
-				.expect("fail to create a Function instance");
-			imp_obj.add_func("send_async_request", func);
-
-			// Register the host function 'send_fileparts_request'
-			let func_ty = FuncType::create(vec![ValType::I32; 9], vec![ValType::I32; 1])
-				.expect("fail to create a FuncType");
-			let boxed_fn = Box::new(this.clone().send_fileparts_request());
-			let func = Function::create(&func_ty, boxed_fn, 0)
-				.expect("fail to create a Function instance");
-			imp_obj.add_func("send_fileparts_request", func);
-
-			// Register the host function 'send_async_fileparts_request'
-			let func_ty =
-				FuncType::create(vec![ValType::I32; 9], vec![]).expect("fail to create a FuncType");
-			let boxed_fn = Box::new(this.clone().send_async_fileparts_request());
-			let func = Function::create(&func_ty, boxed_fn, 0)
-				.expect("fail to create a Function instance");
-			imp_obj.add_func("send_async_fileparts_request", func);
-
-			vm.register_wasm_from_import(ImportObject::Import(imp_obj))
-				.unwrap();
-		}
-
-		this
-	}
-
-	pub fn init(&self) {
+			let mut bg = match this.bg.lock() {
+				Ok(bg) => bg,
+				Err(e) => return Err(format!("{:?}", e)),
+			};
+			if let Err(e) = bg
+				.vm()
+				.register_wasm_from_import(ImportObject::Import(imp_obj))
+			{
+				return Err(format!("Failed to register the host functions. {:?}", e));
+			}
+		}
+
+		Ok(this)
+	}
+
+	pub fn init(&self) -> Result<(), String> {
 		let mut bg = self.bg.lock().unwrap();
-		bg.instantiate();
-		_ = bg.run_wasm("init", vec![]);
+		if let Err(e) = bg.vm().instantiate() {
+			return Err(format!("Failed to instantiate the Wasm. {:?}", e));
+		}
+		match bg.run_wasm("init", vec![]) {
+			Ok(Ok(_)) => Ok(()),
+			Ok(Err(e)) => Err(format!("init failed. {}", e)),
+			Err(e) => Err(format!("Failed to run init. {:?}", e)),
+		}
 	}
 
-	fn parse_params(
-		memory: &Memory,
-		inputs: Vec<WasmValue>,
-	) -> Result<(String, Method, HeaderMap, Vec<u8>), u8> {
+	pub fn shutdown(&self) {
+		let mut bg = self.bg.lock().unwrap();
+		_ = bg.run_wasm("shutdown", vec![]);
+	}
+
+	fn parse_params(memory: &Memory, inputs: Vec<WasmValue>) -> Result<OutboundRequest, Error> {
 		let url = match memory.get_data(inputs[0].to_i32() as u32, inputs[1].to_i32() as u32) {
 			Ok(d) => d,
-			Err(_) => {
-				return Err(WasmEdgeResultCode::TERMINATE as u8);
-			}
-		};
-		let url = match String::from_utf8(url) {
-			Ok(s) => s,
-			Err(_) => {
-				return Err(WasmEdgeResultCode::TERMINATE as u8);
+			Err(e) => {
+				return Err(Error::InvalidRequest(format!(
+					"Failed to read the url. {:?}",
+					e
+				)));
 			}
 		};
 
-		let method: RequestMethod = (inputs[2].to_i32() as u8).into();
-		let method = match Method::from_str(method.to_string().as_str()) {
-			Ok(m) => m,
-			Err(_) => {
-				return Err(WasmEdgeResultCode::TERMINATE as u8);
-			}
+		let headers = match inputs[3].to_i32() as u32 {
+			0 => vec![],
+			headers_pointer => match memory.get_data(headers_pointer, inputs[4].to_i32() as u32) {
+				Ok(d) => d,
+				Err(e) => {
+					return Err(Error::InvalidRequest(format!(
+						"Failed to read the headers. {:?}",
+						e
+					)));
+				}
+			},
 		};
 
-		let headers = match inputs[3].to_i32() as u32 {
-			0 => HeaderMap::new(),
-			headers_pointer => {
-				let headers = match memory.get_data(headers_pointer, inputs[4].to_i32() as u32) {
+		let body = match inputs[5].to_i32() as u32 {
+			0 => vec![],
+			body_pointer => {
+				let body = match memory.get_data(body_pointer, inputs[6].to_i32() as u32) {
 					Ok(d) => d,
-					Err(_) => {
-						return Err(WasmEdgeResultCode::TERMINATE as u8);
+					Err(e) => {
+						return Err(Error::InvalidRequest(format!(
+							"Failed to read the body. {:?}",
+							e
+						)));
 					}
 				};
+				body
+			}
+		};
+
+		Wasm::parse_request(url, inputs[2].to_i32() as u8, headers, body)
+	}
+
+	fn parse_request(
+		url: Vec<u8>,
+		method: u8,
+		headers: Vec<u8>,
+		body: Vec<u8>,
+	) -> Result<OutboundRequest, Error> {
+		let url = match String::from_utf8(url) {
+			Ok(s) => s,
+			Err(e) => {
+				return Err(Error::InvalidUrl(format!("{}", e)));
+			}
+		};
+		if let Err(e) = Url::parse(url.as_str()) {
+			return Err(Error::InvalidUrl(format!("{} {}", e, url)));
+		}
+
+		let headers = match headers.len() {
+			0 => Map::new(),
+			_ => {
 				let headers = match String::from_utf8(headers) {
 					Ok(s) => s,
-					Err(_) => {
-						return Err(WasmEdgeResultCode::TERMINATE as u8);
+					Err(e) => {
+						return Err(Error::InvalidRequest(format!("Invalid headers. {}", e)));
 					}
 				};
 				let headers: Value = match serde_json::from_str(headers.as_str()) {
 					Ok(j) => j,
-					Err(_) => {
-						return Err(WasmEdgeResultCode::TERMINATE as u8);
+					Err(e) => {
+						return Err(Error::InvalidRequest(format!("Invalid headers. {}", e)));
 					}
 				};
-				let headers = match headers {
+				match headers {
 					Value::Object(m) => m,
 					_ => {
-						return Err(WasmEdgeResultCode::TERMINATE as u8);
-					}
-				};
-				let mut header_map = HeaderMap::new();
-				for (k, v) in headers.into_iter() {
-					if let Ok(hn) = HeaderName::from_str(k.as_str()) {
-						if let Ok(hv) = HeaderValue::from_str(v.as_str().unwrap_or_default()) {
-							header_map.insert(hn, hv);
-						}
+						return Err(Error::InvalidRequest(String::from(
+							"Headers are not a JSON object",
+						)));
 					}
 				}
-				header_map
 			}
 		};
 
-		let body = match inputs[5].to_i32() as u32 {
-			0 => vec![],
-			body_pointer => {
-				let body = match memory.get_data(body_pointer, inputs[6].to_i32() as u32) {
-					Ok(d) => d,
-					Err(_) => {
-						return Err(WasmEdgeResultCode::TERMINATE as u8);
-					}
-				};
-				body
+		let options = RequestOptions::from(&headers);
+		let field = |name: &str| headers.get(name).and_then(|v| v.as_str());
+		let callback = field(CALLBACK_HEADER).map(|func_name| {
+			let correlation = field(CORRELATION_HEADER).unwrap_or_default();
+			(func_name.to_string(), correlation.to_string())
+		});
+
+		let method = match RequestMethod::from(method) {
+			// the name of an extension method comes in a pseudo-header
+			RequestMethod::EXTENSION(_) => headers
+				.get(METHOD_HEADER)
+				.and_then(|v| v.as_str())
+				.unwrap_or_default()
+				.to_string(),
+			RequestMethod::UNKNOWN => String::new(),
+			m => m.to_string(),
+		};
+		let method = match Method::from_str(method.as_str()) {
+			Ok(m) => m,
+			Err(_) => {
+				return Err(Error::InvalidRequest(format!(
+					"Invalid method {:?}",
+					method
+				)));
 			}
 		};
 
-		Ok((url, method, headers, body))
+		let mut header_map = HeaderMap::new();
+		for (k, v) in headers.into_iter() {
+			// pseudo-headers are never sent upstream
+			if k.starts_with(':') {
+				continue;
+			}
+			if let Ok(hn) = HeaderName::from_str(k.as_str()) {
+				if let Ok(hv) = HeaderValue::from_str(v.as_str().unwrap_or_default()) {
+					header_map.insert(hn, hv);
+				}
+			}
+		}
+		let headers = header_map;
+
+		Ok(OutboundRequest {
+			url,
+			method,
+			headers,
+			body,
+			options,
+			callback,
+		})
 	}
 
 	fn parse_fileparts_params(
 		memory: &Memory,
 		inputs: Vec<WasmValue>,
-	) -> Result<(String, Method, HeaderMap, Vec<u8>, Vec<u8>), u8> {
+	) -> Result<(OutboundRequest, Vec<u8>), Error> {
 		let fileparts = match inputs[7].to_i32() as u32 {
 			0 => vec![],
 			fileparts_pointer => {
 				let fileparts = match memory.get_data(fileparts_pointer, inputs[8].to_i32() as u32)
 				{
 					Ok(d) => d,
-					Err(_) => {
-						return Err(WasmEdgeResultCode::TERMINATE as u8);
+					Err(e) => {
+						return Err(Error::InvalidRequest(format!(
+							"Failed to read the fileparts. {:?}",
+							e
+						)));
 					}
 				};
 				fileparts
@@ -209,7 +608,7 @@ impl Wasm {
 		};
 
 		match Wasm::parse_params(memory, inputs) {
-			Ok((url, method, headers, body)) => Ok((url, method, headers, body, fileparts)),
+			Ok(req) => Ok((req, fileparts)),
 			Err(e) => Err(e),
 		}
 	}
@@ -225,8 +624,11 @@ impl Wasm {
 		}
 	}
 
+	// The result is [body pointer, body len, status] followed by
+	// [headers pointer, headers len] if the headers are requested
 	fn settle_result(
 		status: u16,
+		ret_headers: Option<String>,
 		ret_body: Vec<u8>,
 		memory: &mut Memory,
 		vm: &Vm,
@@ -238,7 +640,19 @@ impl Wasm {
 		};
 
 		let status = (status as i32).to_le_bytes().try_into().unwrap();
-		let whole = [body_pointer, body_len, status].concat();
+		let mut whole = [body_pointer, body_len, status].concat();
+
+		if let Some(ret_headers) = ret_headers {
+			let ret_headers = ret_headers.into_bytes();
+			let headers_len: [u8; 4] = (ret_headers.len() as i32).to_le_bytes().try_into().unwrap();
+			let headers_pointer: [u8; 4] = match Wasm::set_wasm_memory(ret_headers, memory, vm) {
+				Ok(p) => p.to_le_bytes().try_into().unwrap(),
+				Err(e) => return Err(e),
+			};
+			whole.extend(headers_pointer);
+			whole.extend(headers_len);
+		}
+
 		let whole_pointer = match Wasm::set_wasm_memory(whole, memory, vm) {
 			Ok(p) => p,
 			Err(e) => return Err(e),
@@ -246,31 +660,41 @@ impl Wasm {
 		Ok(vec![WasmValue::from_i32(whole_pointer)])
 	}
 
-	fn send_request(self) -> impl Fn(Vec<WasmValue>) -> Result<Vec<WasmValue>, u8> {
+	// Errors are handed to the guest as status 0 with a JSON body
+	fn settle_exchange(
+		ret: Exchange,
+		with_headers: bool,
+		memory: &mut Memory,
+		vm: &Vm,
+	) -> Result<Vec<WasmValue>, u8> {
+		match ret {
+			Ok((status, ret_headers, ret_body)) => {
+				let ret_headers = with_headers.then_some(ret_headers);
+				Wasm::settle_result(status, ret_headers, ret_body, memory, vm)
+			}
+			Err(e) => {
+				let ret_headers = with_headers.then(|| String::from("{}"));
+				Wasm::settle_result(0, ret_headers, e.to_vec(), memory, vm)
+			}
+		}
+	}
+
+	// Blocks the host calls of every instance until the response, kept for the
+	// guests built before begin_request
+	fn send_request(
+		self,
+		with_headers: bool,
+	) -> impl Fn(Vec<WasmValue>) -> Result<Vec<WasmValue>, u8> {
 		move |inputs: Vec<WasmValue>| -> Result<Vec<WasmValue>, u8> {
 			let mut bg = self.bg.lock().unwrap();
 			let mut mbg = bg.borrow_mut().clone();
 			drop(bg);
-			let mut memory = mbg
-				.vm()
-				.active_module()
-				.unwrap()
-				.get_memory("memory")
-				.unwrap();
-
-			let (url, method, headers, body) = match Wasm::parse_params(&memory, inputs) {
-				Ok(p) => p,
-				Err(e) => return Err(e),
-			};
+			let mut memory = wasm_memory(mbg.vm())?;
 
-			match Wasm::do_request(url, method, headers, body) {
-				Ok((status, ret_body)) => {
-					let vm = mbg.vm();
-					Wasm::settle_result(status, ret_body, &mut memory, vm)
-				}
+			let ret = Wasm::parse_params(&memory, inputs)
+				.and_then(|req| block_on(Wasm::do_request(&self.outbound, &self.running(), req)));
 
-				Err(_) => Err(WasmEdgeResultCode::FAIL as u8),
-			}
+			Wasm::settle_exchange(ret, with_headers, &mut memory, mbg.vm())
 		}
 	}
 
@@ -279,52 +703,51 @@ impl Wasm {
 			let mut bg = self.bg.lock().unwrap();
 			let mut mbg = bg.borrow_mut().clone();
 			drop(bg);
-			let memory = mbg
-				.vm()
-				.active_module()
-				.unwrap()
-				.get_memory("memory")
-				.unwrap();
+			let memory = wasm_memory(mbg.vm())?;
 
-			let (url, method, headers, body) = match Wasm::parse_params(&memory, inputs) {
+			let mut req = match Wasm::parse_params(&memory, inputs) {
 				Ok(p) => p,
-				Err(e) => return Err(e),
+				Err(e) => {
+					eprintln!("Dropped an async request. {}", redact(&e.to_string()));
+					return Ok(vec![]);
+				}
 			};
+			let callback = self.callback_target(&mut req);
 
-			tokio::spawn(async move {
-				let _ = Wasm::do_request(url, method, headers, body);
+			let outbound = self.outbound.clone();
+			let sender = self.running();
+			TASKS.spawn(async move {
+				let ret = Wasm::do_request(&outbound, &sender, req).await;
+				if let Some((pool, func_name, correlation)) = callback {
+					Wasm::complete(pool, func_name, correlation, ret).await;
+				}
 			});
 
 			Ok(vec![])
 		}
 	}
 
-	fn send_fileparts_request(self) -> impl Fn(Vec<WasmValue>) -> Result<Vec<WasmValue>, u8> {
+	fn send_fileparts_request(
+		self,
+		with_headers: bool,
+	) -> impl Fn(Vec<WasmValue>) -> Result<Vec<WasmValue>, u8> {
 		move |inputs: Vec<WasmValue>| -> Result<Vec<WasmValue>, u8> {
 			let mut bg = self.bg.lock().unwrap();
 			let mut mbg = bg.borrow_mut().clone();
 			drop(bg);
-			let mut memory = mbg
-				.vm()
-				.active_module()
-				.unwrap()
-				.get_memory("memory")
-				.unwrap();
-
-			let (url, method, headers, body, fileparts) =
-				match Wasm::parse_fileparts_params(&memory, inputs) {
-					Ok(p) => p,
-					Err(e) => return Err(e),
-				};
+			let mut memory = wasm_memory(mbg.vm())?;
 
-			match Wasm::do_fileparts_request(url, method, headers, body, fileparts) {
-				Ok((status, ret_body)) => {
-					let vm = mbg.vm();
-					Wasm::settle_result(status, ret_body, &mut memory, vm)
-				}
+			let ret = Wasm::parse_fileparts_params(&memory, inputs).and_then(|(req, fileparts)| {
+				let sender = self.running();
+				block_on(Wasm::do_fileparts_request(
+					&self.outbound,
+					&sender,
+					req,
+					fileparts,
+				))
+			});
 
-				Err(_) => Err(WasmEdgeResultCode::FAIL as u8),
-			}
+			Wasm::settle_exchange(ret, with_headers, &mut memory, mbg.vm())
 		}
 	}
 
@@ -333,79 +756,457 @@ impl Wasm {
 			let mut bg = self.bg.lock().unwrap();
 			let mut mbg = bg.borrow_mut().clone();
 			drop(bg);
-			let memory = mbg
-				.vm()
-				.active_module()
-				.unwrap()
-				.get_memory("memory")
-				.unwrap();
-
-			let (url, method, headers, body, fileparts) =
-				match Wasm::parse_fileparts_params(&memory, inputs) {
-					Ok(p) => p,
-					Err(e) => return Err(e),
-				};
+			let memory = wasm_memory(mbg.vm())?;
 
-			tokio::spawn(async move {
-				let _ = Wasm::do_fileparts_request(url, method, headers, body, fileparts);
+			let (mut req, fileparts) = match Wasm::parse_fileparts_params(&memory, inputs) {
+				Ok(p) => p,
+				Err(e) => {
+					eprintln!("Dropped an async request. {}", redact(&e.to_string()));
+					return Ok(vec![]);
+				}
+			};
+			let callback = self.callback_target(&mut req);
+
+			let outbound = self.outbound.clone();
+			let sender = self.running();
+			TASKS.spawn(async move {
+				let ret = Wasm::do_fileparts_request(&outbound, &sender, req, fileparts).await;
+				if let Some((pool, func_name, correlation)) = callback {
+					Wasm::complete(pool, func_name, correlation, ret).await;
+				}
 			});
 
 			Ok(vec![])
 		}
 	}
 
-	pub fn execute(
-		&self,
-		func_name: &str,
-		headers: &str,
-		queries: &str,
-		body: &Vec<u8>,
-	) -> Result<(u16, String, Vec<u8>), String> {
-		let params = vec![
-			Param::String(headers),
-			Param::String(queries),
-			Param::VecU8(body),
-		];
-		let mut bg = self.bg.lock().unwrap();
-		let mut mbg = bg.borrow_mut().clone();
-		drop(bg);
-		match mbg.run_wasm(func_name, params) {
-			Ok(rv) => {
-				if let Ok(mut v) = rv {
-					if v.len() == 3 {
-						if let Ok(ret_body) = v.pop().unwrap().downcast::<Vec<u8>>() {
-							if let Ok(ret_headers) = v.pop().unwrap().downcast::<String>() {
-								if let Ok(ret_status) = v.pop().unwrap().downcast::<u16>() {
-									return Ok((*ret_status, *ret_headers, *ret_body));
-								}
-							}
-						}
+	// Holding on to the pool, so the callback runs on the module that sent the
+	// request even if it is reloaded meanwhile
+	fn callback_target(&self, req: &mut OutboundRequest) -> Option<(Arc<Pool>, String, String)> {
+		let (func_name, correlation) = req.callback.take()?;
+		let pool = self.outbound.pool()?;
+		Some((pool, func_name, correlation))
+	}
+
+	// Call the guest's callback with the outcome of an async request, in the
+	// handler signature with the response as headers and body
+	async fn complete(
+		pool: Arc<Pool>,
+		func_name: String,
+		correlation: String,
+		ret: Result<(u16, String, Vec<u8>), Error>,
+	) {
+		let (status, headers, body) = match ret {
+			Ok(ret) => ret,
+			Err(e) => (0, String::from("{}"), e.to_vec()),
+		};
+		let context = CallbackContext {
+			correlation,
+			status,
+		}
+		.to_string();
+
+		let name = func_name.clone();
+		let job = Job {
+			func_name,
+			headers,
+			queries: String::from("{}"),
+			context: String::new(),
+			body,
+			fileparts: None,
+		};
+		let ret = tokio::task::spawn_blocking(move || {
+			pool.checkout()?.call(&job, Context::Callback(context))
+		})
+		.await
+		.unwrap_or_else(|e| Err(format!("{:?}", e)));
+		if let Err(e) = ret {
+			eprintln!("Failed to call the callback {}. {}", name, redact(&e));
+		}
+	}
+
+	// The result is [results pointer, results len], blocking like send_request
+	fn send_batch_request(self) -> impl Fn(Vec<WasmValue>) -> Result<Vec<WasmValue>, u8> {
+		move |inputs: Vec<WasmValue>| -> Result<Vec<WasmValue>, u8> {
+			let mut bg = self.bg.lock().unwrap();
+			let mut mbg = bg.borrow_mut().clone();
+			drop(bg);
+			let mut memory = wasm_memory(mbg.vm())?;
+
+			let (batch, deadline) = Wasm::read_batch(&memory, &inputs);
+			let results = block_on(Wasm::do_batch(
+				self.outbound.clone(),
+				self.running(),
+				batch,
+				deadline,
+			));
+			Wasm::settle_batch(results, &mut memory, mbg.vm())
+		}
+	}
+
+	fn read_batch(
+		memory: &Memory,
+		inputs: &[WasmValue],
+	) -> (Result<Batch, Error>, Option<Instant>) {
+		let batch = Wasm::read_data(memory, inputs[0].to_i32(), inputs[1].to_i32(), "batch")
+			.and_then(|raw| Batch::try_from(raw.as_slice()));
+		let deadline = match inputs[2].to_i32() {
+			0 => None,
+			ms => Some(Instant::now() + Duration::from_millis(ms as u64)),
+		};
+		(batch, deadline)
+	}
+
+	// An unreadable batch gets its error as the only result, the glue hands it
+	// to every request
+	fn batch_error(e: Error) -> Vec<u8> {
+		BatchResults {
+			inner: vec![BatchResult {
+				status: 0,
+				headers: String::from("{}"),
+				body: e.to_vec(),
+			}],
+		}
+		.to_vec()
+	}
+
+	// The requests run concurrently and those still running at the deadline
+	// fail with a timeout
+	async fn do_batch(
+		outbound: Arc<Outbound>,
+		sender: String,
+		batch: Result<Batch, Error>,
+		deadline: Option<Instant>,
+	) -> Vec<u8> {
+		let batch = match batch {
+			Ok(b) => b,
+			Err(e) => return Wasm::batch_error(e),
+		};
+
+		let handles: Vec<JoinHandle<Exchange>> = batch
+			.inner
+			.into_iter()
+			.map(|item| {
+				let outbound = outbound.clone();
+				let sender = sender.clone();
+				let req = Wasm::parse_request(
+					item.url.into_bytes(),
+					item.method,
+					item.headers,
+					item.body,
+				);
+				tokio::spawn(async move {
+					match req {
+						Ok(req) => Wasm::do_request(&outbound, &sender, req).await,
+						Err(e) => Err(e),
 					}
+				})
+			})
+			.collect();
+
+		let mut results = vec![];
+		for mut h in handles.into_iter() {
+			let ret = match deadline {
+				Some(deadline) => match time::timeout_at(deadline, &mut h).await {
+					Ok(ret) => ret,
+					Err(_) => {
+						h.abort();
+						results.push(Err(Error::Timeout(String::from("Batch deadline exceeded"))));
+						continue;
+					}
+				},
+				None => h.await,
+			};
+			results.push(match ret {
+				Ok(ret) => ret,
+				Err(e) => Err(Error::Other(format!("{:?}", e))),
+			});
+		}
+
+		BatchResults {
+			inner: results
+				.into_iter()
+				.map(|ret| match ret {
+					Ok((status, headers, body)) => BatchResult {
+						status,
+						headers,
+						body,
+					},
+					Err(e) => BatchResult {
+						status: 0,
+						headers: String::from("{}"),
+						body: e.to_vec(),
+					},
+				})
+				.collect(),
+		}
+		.to_vec()
+	}
+
+	// Start the request on the runtime and return its handle for poll_request
+	fn begin_request(self) -> impl Fn(Vec<WasmValue>) -> Result<Vec<WasmValue>, u8> {
+		move |inputs: Vec<WasmValue>| -> Result<Vec<WasmValue>, u8> {
+			let mut bg = self.bg.lock().unwrap();
+			let mut mbg = bg.borrow_mut().clone();
+			drop(bg);
+			let memory = wasm_memory(mbg.vm())?;
+
+			let req = Wasm::parse_params(&memory, inputs);
+			let outbound = self.outbound.clone();
+			let sender = self.running();
+			let handle = self.pending.lock().unwrap().begin(async move {
+				Outcome::Exchange(match req {
+					Ok(req) => Wasm::do_request(&outbound, &sender, req).await,
+					Err(e) => Err(e),
+				})
+			});
+			Ok(vec![WasmValue::from_i32(handle)])
+		}
+	}
+
+	fn begin_fileparts_request(self) -> impl Fn(Vec<WasmValue>) -> Result<Vec<WasmValue>, u8> {
+		move |inputs: Vec<WasmValue>| -> Result<Vec<WasmValue>, u8> {
+			let mut bg = self.bg.lock().unwrap();
+			let mut mbg = bg.borrow_mut().clone();
+			drop(bg);
+			let memory = wasm_memory(mbg.vm())?;
+
+			let req = Wasm::parse_fileparts_params(&memory, inputs);
+			let outbound = self.outbound.clone();
+			let sender = self.running();
+			let handle = self.pending.lock().unwrap().begin(async move {
+				Outcome::Exchange(match req {
+					Ok((req, fileparts)) => {
+						Wasm::do_fileparts_request(&outbound, &sender, req, fileparts).await
+					}
+					Err(e) => Err(e),
+				})
+			});
+			Ok(vec![WasmValue::from_i32(handle)])
+		}
+	}
+
+	fn begin_batch_request(self) -> impl Fn(Vec<WasmValue>) -> Result<Vec<WasmValue>, u8> {
+		move |inputs: Vec<WasmValue>| -> Result<Vec<WasmValue>, u8> {
+			let mut bg = self.bg.lock().unwrap();
+			let mut mbg = bg.borrow_mut().clone();
+			drop(bg);
+			let memory = wasm_memory(mbg.vm())?;
+
+			let (batch, deadline) = Wasm::read_batch(&memory, &inputs);
+			let outbound = self.outbound.clone();
+			let sender = self.running();
+			let handle = self.pending.lock().unwrap().begin(async move {
+				Outcome::Batch(Wasm::do_batch(outbound, sender, batch, deadline).await)
+			});
+			Ok(vec![WasmValue::from_i32(handle)])
+		}
+	}
+
+	// The result is 0 while the request runs, then the result of the blocking
+	// function matching the kind asked for
+	fn poll_request(self) -> impl Fn(Vec<WasmValue>) -> Result<Vec<WasmValue>, u8> {
+		move |inputs: Vec<WasmValue>| -> Result<Vec<WasmValue>, u8> {
+			let mut bg = self.bg.lock().unwrap();
+			let mut mbg = bg.borrow_mut().clone();
+			drop(bg);
+			let mut memory = wasm_memory(mbg.vm())?;
+
+			let outcome = match self.pending.lock().unwrap().poll(inputs[0].to_i32()) {
+				Some(outcome) => outcome,
+				None => return Ok(vec![WasmValue::from_i32(0)]),
+			};
+			let kind = inputs[1].to_i32();
+			let mismatch = || Error::InvalidRequest(String::from("The handle is of another kind"));
+			let vm = mbg.vm();
+			match kind {
+				POLL_BATCH => {
+					let results = match outcome {
+						Ok(Outcome::Batch(results)) => results,
+						Ok(Outcome::Exchange(_)) => Wasm::batch_error(mismatch()),
+						Err(e) => Wasm::batch_error(e),
+					};
+					Wasm::settle_batch(results, &mut memory, vm)
+				}
+				_ => {
+					let ret = match outcome {
+						Ok(Outcome::Exchange(ret)) => ret,
+						Ok(Outcome::Batch(_)) => Err(mismatch()),
+						Err(e) => Err(e),
+					};
+					let with_headers = kind == POLL_RESULT_WITH_HEADERS;
+					Wasm::settle_exchange(ret, with_headers, &mut memory, vm)
 				}
-				Err(String::from("Invalid return values"))
 			}
-			Err(e) => Err(format!("{:?}", e)),
 		}
 	}
 
-	pub fn execute_fileparts(
+	// The result is [results pointer, results len]
+	fn settle_batch(results: Vec<u8>, memory: &mut Memory, vm: &Vm) -> Result<Vec<WasmValue>, u8> {
+		let results_len: [u8; 4] = (results.len() as i32).to_le_bytes();
+		let results_pointer: [u8; 4] = Wasm::set_wasm_memory(results, memory, vm)?.to_le_bytes();
+		let whole = [results_pointer, results_len].concat();
+		let whole_pointer = Wasm::set_wasm_memory(whole, memory, vm)?;
+		Ok(vec![WasmValue::from_i32(whole_pointer)])
+	}
+
+	fn read_data(memory: &Memory, pointer: i32, len: i32, name: &str) -> Result<Vec<u8>, Error> {
+		match pointer as u32 {
+			0 => Ok(vec![]),
+			pointer => match memory.get_data(pointer, len as u32) {
+				Ok(d) => Ok(d),
+				Err(e) => Err(Error::InvalidRequest(format!(
+					"Failed to read the {}. {:?}",
+					name, e
+				))),
+			},
+		}
+	}
+
+	fn do_kv(
 		&self,
-		func_name: &str,
-		headers: &str,
-		queries: &str,
-		body: &Vec<u8>,
-		fileparts: &Vec<u8>,
-	) -> Result<(u16, String, Vec<u8>), String> {
-		let params = vec![
-			Param::String(headers),
-			Param::String(queries),
-			Param::VecU8(body),
-			Param::VecU8(fileparts),
+		op: KvOp,
+		memory: &Memory,
+		inputs: &[WasmValue],
+	) -> Result<(u16, Vec<u8>), Error> {
+		let key = Wasm::read_data(memory, inputs[0].to_i32(), inputs[1].to_i32(), "key")?;
+		let key = match String::from_utf8(key) {
+			Ok(k) => k,
+			Err(e) => return Err(Error::InvalidRequest(format!("Invalid key. {}", e))),
+		};
+
+		let ret = match op {
+			KvOp::Get => self.kv.get(&key).map(|v| match v {
+				Some(v) => (200, v),
+				None => (404, vec![]),
+			}),
+			KvOp::Set => {
+				if key.is_empty() {
+					return Err(Error::InvalidRequest(String::from("Empty key")));
+				}
+				let value =
+					Wasm::read_data(memory, inputs[2].to_i32(), inputs[3].to_i32(), "value")?;
+				// a TTL of 0 keeps the value until it is replaced or deleted
+				let ttl = match inputs[4].to_i64() {
+					ttl_ms if ttl_ms > 0 => Some(Duration::from_millis(ttl_ms as u64)),
+					_ => None,
+				};
+				self.kv.set(&key, value, ttl).map(|_| (200, vec![]))
+			}
+			KvOp::Delete => self
+				.kv
+				.delete(&key)
+				.map(|deleted| (if deleted { 200 } else { 404 }, vec![])),
+			KvOp::ListPrefix => self
+				.kv
+				.list_prefix(&key)
+				.map(|keys| (200, serde_json::to_vec(&keys).unwrap())),
+		};
+		ret.map_err(Error::Other)
+	}
+
+	// The result is the same [body pointer, body len, status] as send_request's,
+	// with 404 for a missing key
+	fn kv_call(self, op: KvOp) -> impl Fn(Vec<WasmValue>) -> Result<Vec<WasmValue>, u8> {
+		move |inputs: Vec<WasmValue>| -> Result<Vec<WasmValue>, u8> {
+			let mut bg = self.bg.lock().unwrap();
+			let mut mbg = bg.borrow_mut().clone();
+			drop(bg);
+			let mut memory = wasm_memory(mbg.vm())?;
+
+			let ret = self.do_kv(op, &memory, &inputs);
+
+			let vm = mbg.vm();
+			match ret {
+				Ok((status, body)) => Wasm::settle_result(status, None, body, &mut memory, vm),
+				Err(e) => Wasm::settle_result(0, None, e.to_vec(), &mut memory, vm),
+			}
+		}
+	}
+
+	// The result is [value pointer, value len, status] with 404 for an unknown secret
+	fn get_secret(self) -> impl Fn(Vec<WasmValue>) -> Result<Vec<WasmValue>, u8> {
+		move |inputs: Vec<WasmValue>| -> Result<Vec<WasmValue>, u8> {
+			let mut bg = self.bg.lock().unwrap();
+			let mut mbg = bg.borrow_mut().clone();
+			drop(bg);
+			let mut memory = wasm_memory(mbg.vm())?;
+
+			let ret = Wasm::read_data(&memory, inputs[0].to_i32(), inputs[1].to_i32(), "name").map(
+				|name| match self.injected.secret(&String::from_utf8_lossy(&name)) {
+					Some(value) => (200, value.clone().into_bytes()),
+					None => (404, vec![]),
+				},
+			);
+
+			let vm = mbg.vm();
+			match ret {
+				Ok((status, body)) => Wasm::settle_result(status, None, body, &mut memory, vm),
+				Err(e) => Wasm::settle_result(0, None, e.to_vec(), &mut memory, vm),
+			}
+		}
+	}
+
+	// The result is [context pointer, context len, status], with an error if
+	// the function wasn't called that way
+	fn get_context(self, callback: bool) -> impl Fn(Vec<WasmValue>) -> Result<Vec<WasmValue>, u8> {
+		move |_: Vec<WasmValue>| -> Result<Vec<WasmValue>, u8> {
+			let mut bg = self.bg.lock().unwrap();
+			let mut mbg = bg.borrow_mut().clone();
+			drop(bg);
+			let mut memory = wasm_memory(mbg.vm())?;
+
+			let ret = match (&*self.context.lock().unwrap(), callback) {
+				(Context::Request(c), false) | (Context::Callback(c), true) => Ok(c.clone()),
+				(Context::Request(_), true) => Err(Error::InvalidRequest(String::from(
+					"Not called as a callback",
+				))),
+				(Context::Callback(_), false) => Err(Error::InvalidRequest(String::from(
+					"Called as a callback, not for a request",
+				))),
+			};
+			let vm = mbg.vm();
+			match ret {
+				Ok(context) => {
+					Wasm::settle_result(200, None, context.into_bytes(), &mut memory, vm)
+				}
+				Err(e) => Wasm::settle_result(0, None, e.to_vec(), &mut memory, vm),
+			}
+		}
+	}
+
+	// Whether a call trapped, so the instance shouldn't be reused
+	pub fn failed(&self) -> bool {
+		self.failed.load(Ordering::Relaxed)
+	}
+
+	fn running(&self) -> String {
+		self.running.lock().unwrap().clone()
+	}
+
+	// Call the handler with the headers, queries and body of the job, and the
+	// file parts if it has some
+	pub fn execute(&self, job: &Job) -> Result<(u16, String, Vec<u8>), String> {
+		self.call(job, Context::Request(job.context.clone()))
+	}
+
+	fn call(&self, job: &Job, context: Context) -> Result<(u16, String, Vec<u8>), String> {
+		let mut params = vec![
+			Param::String(&job.headers),
+			Param::String(&job.queries),
+			Param::VecU8(&job.body),
 		];
+		if let Some(fileparts) = &job.fileparts {
+			params.push(Param::VecU8(fileparts));
+		}
+		*self.running.lock().unwrap() = job.func_name.clone();
+		*self.context.lock().unwrap() = context;
 		let mut bg = self.bg.lock().unwrap();
 		let mut mbg = bg.borrow_mut().clone();
 		drop(bg);
-		match mbg.run_wasm(func_name, params) {
+		let ret = mbg.run_wasm(&job.func_name, params);
+		self.pending.lock().unwrap().clear();
+		match ret {
 			Ok(rv) => {
 				if let Ok(mut v) = rv {
 					if v.len() == 3 {
@@ -420,87 +1221,291 @@ impl Wasm {
 				}
 				Err(String::from("Invalid return values"))
 			}
-			Err(e) => Err(format!("{:?}", e)),
+			// only a trap can leave the guest's memory inconsistent
+			Err(e) => {
+				self.failed.store(true, Ordering::Relaxed);
+				Err(format!("{:?}", e))
+			}
 		}
 	}
 
-	fn do_request(
-		url: String,
-		method: Method,
-		headers: HeaderMap,
-		body: Vec<u8>,
-	) -> Result<(u16, Vec<u8>), String> {
-		tokio::task::block_in_place(move || {
-			let c = ClientBuilder::new()
-				.timeout(Duration::from_secs(TIMEOUT))
-				.build()
-				.unwrap();
-			match c.request(method, url).headers(headers).body(body).send() {
-				Ok(r) => {
-					let status = r.status().as_u16();
-					match r.bytes() {
-						Ok(b) => Ok((status, b.as_ref().to_vec())),
-						Err(e) => Err(format!("{:?}", e)),
+	async fn read_response(
+		mut r: Response,
+		options: &RequestOptions,
+	) -> Result<(u16, String, Vec<u8>), Error> {
+		let status = r.status().as_u16();
+		let ret_headers = encode_headers(r.headers());
+		let body = match options.max_response_size {
+			Some(max) => {
+				if r.content_length().unwrap_or_default() > max as u64 {
+					return Err(Error::TooLarge(format!(
+						"Response of {} bytes exceeds {} bytes",
+						r.content_length().unwrap_or_default(),
+						max
+					)));
+				}
+				// stop reading as soon as the body grows past the limit
+				let mut body = vec![];
+				loop {
+					match r.chunk().await {
+						Ok(Some(chunk)) => body.extend_from_slice(&chunk),
+						Ok(None) => break,
+						Err(e) => return Err(request_error(e)),
+					}
+					if body.len() > max {
+						return Err(Error::TooLarge(format!("Response exceeds {} bytes", max)));
 					}
 				}
-				Err(e) => Err(format!("{:?}", e)),
+				body
 			}
-		})
+			None => match r.bytes().await {
+				Ok(b) => b.as_ref().to_vec(),
+				Err(e) => return Err(request_error(e)),
+			},
+		};
+		Ok((status, ret_headers, body))
 	}
 
-	fn do_fileparts_request(
-		url: String,
-		method: Method,
-		headers: HeaderMap,
-		body: Vec<u8>,
-		fileparts: Vec<u8>,
-	) -> Result<(u16, Vec<u8>), String> {
-		tokio::task::block_in_place(move || {
-			let c = ClientBuilder::new()
-				.timeout(Duration::from_secs(TIMEOUT))
-				.build()
-				.unwrap();
-
-			let mut request = multipart::Form::new();
-			match serde_json::from_slice(&body) {
-				Ok(Value::Object(b)) => {
-					request = b.into_iter().fold(request, |accum, (k, v)| {
-						if v.is_string() {
-							return accum.text(k, v.as_str().unwrap().to_string());
-						}
-						accum
-					});
-				}
-				_ => (),
+	// With a cassette the exchange is either served from it or written to it
+	async fn exchange<F>(
+		outbound: &Outbound,
+		recorded: Option<RecordedRequest>,
+		send: F,
+	) -> Result<(u16, String, Vec<u8>), Error>
+	where
+		F: Future<Output = Result<(u16, String, Vec<u8>), Error>>,
+	{
+		match (&outbound.cassette, recorded) {
+			(Some(cassette), Some(recorded)) if cassette.is_replay() => cassette.replay(&recorded),
+			(Some(cassette), Some(recorded)) => {
+				let ret = send.await;
+				cassette.record(recorded, &ret);
+				ret
 			}
+			_ => send.await,
+		}
+	}
 
-			let fps: FileParts = fileparts.into();
-			for f in fps.inner.into_iter() {
-				if let Ok(part) = multipart::Part::bytes(f.bytes)
-					.file_name(f.file_name)
-					.mime_str(&f.mime_str)
-				{
-					request = request.part("file", part);
-				}
+	async fn do_request(
+		outbound: &Outbound,
+		sender: &str,
+		req: OutboundRequest,
+	) -> Result<(u16, String, Vec<u8>), Error> {
+		if let Some(mock) = outbound.mocks.find(&req.method, &req.url) {
+			return mock.respond().await;
+		}
+		let recorded = outbound
+			.cassette
+			.as_ref()
+			.map(|_| RecordedRequest::new(&req.method, &req.url, &req.headers, &req.body, None));
+		Wasm::exchange(outbound, recorded, Wasm::send(outbound, sender, req)).await
+	}
+
+	async fn do_fileparts_request(
+		outbound: &Outbound,
+		sender: &str,
+		req: OutboundRequest,
+		fileparts: Vec<u8>,
+	) -> Result<(u16, String, Vec<u8>), Error> {
+		if let Some(mock) = outbound.mocks.find(&req.method, &req.url) {
+			return mock.respond().await;
+		}
+		let recorded = outbound.cassette.as_ref().map(|_| {
+			RecordedRequest::new(
+				&req.method,
+				&req.url,
+				&req.headers,
+				&req.body,
+				Some(fileparts.as_slice()),
+			)
+		});
+		let send = Wasm::send_fileparts(outbound, sender, req, fileparts);
+		Wasm::exchange(outbound, recorded, send).await
+	}
+
+	async fn send(
+		outbound: &Outbound,
+		sender: &str,
+		req: OutboundRequest,
+	) -> Result<(u16, String, Vec<u8>), Error> {
+		let c = outbound.client(sender, &req.url, &req.options).await?;
+		let mut request = c
+			.request(req.method, outbound.rewrite(req.url))
+			.headers(req.headers)
+			.body(req.body);
+		if let Some(timeout_ms) = req.options.timeout_ms {
+			request = request.timeout(Duration::from_millis(timeout_ms));
+		}
+		match request.send().await {
+			Ok(r) => Wasm::read_response(r, &req.options).await,
+			Err(e) => Err(request_error(e)),
+		}
+	}
+
+	async fn send_fileparts(
+		outbound: &Outbound,
+		sender: &str,
+		req: OutboundRequest,
+		fileparts: Vec<u8>,
+	) -> Result<(u16, String, Vec<u8>), Error> {
+		let c = outbound.client(sender, &req.url, &req.options).await?;
+
+		let mut form = multipart::Form::new();
+		match serde_json::from_slice(&req.body) {
+			Ok(Value::Object(b)) => {
+				form = b.into_iter().fold(form, |accum, (k, v)| {
+					if v.is_string() {
+						return accum.text(k, v.as_str().unwrap().to_string());
+					}
+					accum
+				});
 			}
-			match c
-				.request(method, url)
-				.headers(headers)
-				.multipart(request)
-				.send()
+			_ => (),
+		}
+
+		let fps: FileParts = fileparts.into();
+		for f in fps.inner.into_iter() {
+			if let Ok(part) = multipart::Part::bytes(f.bytes)
+				.file_name(f.file_name)
+				.mime_str(&f.mime_str)
 			{
-				Ok(r) => {
-					let status = r.status().as_u16();
-					match r.bytes() {
-						Ok(b) => Ok((status, b.as_ref().to_vec())),
-						Err(e) => Err(format!("{:?}", e)),
-					}
-				}
-				Err(e) => Err(format!("{:?}", e)),
+				form = form.part("file", part);
 			}
-		})
+		}
+		let mut request = c
+			.request(req.method, outbound.rewrite(req.url))
+			.headers(req.headers)
+			.multipart(form);
+		if let Some(timeout_ms) = req.options.timeout_ms {
+			request = request.timeout(Duration::from_millis(timeout_ms));
+		}
+		match request.send().await {
+			Ok(r) => Wasm::read_response(r, &req.options).await,
+			Err(e) => Err(request_error(e)),
+		}
 	}
 }
 
 unsafe impl Send for Wasm {}
 unsafe impl Sync for Wasm {}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use tokio::{
+		io::{AsyncReadExt, AsyncWriteExt},
+		net::TcpListener,
+	};
+
+	use crate::egress::Egress;
+	use crate::mocks::Mocks;
+	use crate::route_config::{self, OutboundConfig};
+
+	const GUESTS: usize = 8;
+	const UPSTREAM_DELAY: Duration = Duration::from_millis(100);
+
+	// Answers every request with `ok` after the delay
+	async fn slow_upstream() -> String {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = listener.local_addr().unwrap();
+		tokio::spawn(async move {
+			while let Ok((mut stream, _)) = listener.accept().await {
+				tokio::spawn(async move {
+					let mut buf = [0; 1024];
+					_ = stream.read(&mut buf).await;
+					time::sleep(UPSTREAM_DELAY).await;
+					_ = stream
+						.write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nok")
+						.await;
+				});
+			}
+		});
+		format!("http://{}/", addr)
+	}
+
+	fn outbound() -> Arc<Outbound> {
+		let config = route_config::Config::parse("route = []").unwrap();
+		let outbound_config = OutboundConfig {
+			no_proxy: Some(vec![String::from("*")]),
+			..Default::default()
+		};
+		let outbound = Outbound::new(
+			None,
+			Egress::new(&config),
+			Mocks::default(),
+			None,
+			&outbound_config,
+		);
+		Arc::new(outbound.unwrap())
+	}
+
+	fn request(url: &str) -> OutboundRequest {
+		let method = RequestMethod::GET.code();
+		Wasm::parse_request(url.as_bytes().to_vec(), method, vec![], vec![]).unwrap()
+	}
+
+	// Time for the guests to get a response each, with every host call holding
+	// one lock the way the wasmedge-sys trampoline holds HOST_FUNCS
+	async fn guests(polled: bool) -> Duration {
+		let url = slow_upstream().await;
+		let outbound = outbound();
+		let host_funcs = Arc::new(Mutex::new(()));
+
+		let started = Instant::now();
+		let guests: Vec<_> = (0..GUESTS)
+			.map(|_| {
+				let (url, outbound, host_funcs) =
+					(url.clone(), outbound.clone(), host_funcs.clone());
+				tokio::task::spawn_blocking(move || {
+					if !polled {
+						let _host_funcs = host_funcs.lock().unwrap();
+						return block_on(Wasm::do_request(&outbound, "f", request(&url)));
+					}
+
+					let mut pending = Pending::default();
+					let handle = {
+						let _host_funcs = host_funcs.lock().unwrap();
+						let req = request(&url);
+						pending.begin(async move {
+							Outcome::Exchange(Wasm::do_request(&outbound, "f", req).await)
+						})
+					};
+					// the glue sleeps between polls the same way
+					let mut pause = Duration::from_millis(1);
+					loop {
+						let polled = {
+							let _host_funcs = host_funcs.lock().unwrap();
+							pending.poll(handle)
+						};
+						match polled {
+							Some(Ok(Outcome::Exchange(ret))) => return ret,
+							Some(_) => panic!("not an exchange"),
+							None => std::thread::sleep(pause),
+						}
+						pause = (pause * 2).min(Duration::from_millis(8));
+					}
+				})
+			})
+			.collect();
+		for guest in guests {
+			let (status, _, body) = guest.await.unwrap().unwrap();
+			assert_eq!((status, body.as_slice()), (200, b"ok".as_slice()));
+		}
+		started.elapsed()
+	}
+
+	// A benchmark, run with `cargo test --release host_funcs_lock -- --ignored --nocapture`
+	#[tokio::test(flavor = "multi_thread")]
+	#[ignore]
+	async fn host_funcs_lock() {
+		let blocking = guests(false).await;
+		let polled = guests(true).await;
+		println!(
+			"{} guests, upstream answering in {:?}: blocking {:?}, polled {:?}",
+			GUESTS, UPSTREAM_DELAY, blocking, polled
+		);
+		// the blocking guests wait for each other's responses
+		assert!(blocking >= UPSTREAM_DELAY * GUESTS as u32);
+		assert!(polled < UPSTREAM_DELAY * 2);
+	}
+}
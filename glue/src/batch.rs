@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+
+use crate::error::Error;
+use crate::options::RequestOptions;
+use crate::RequestMethod;
+
+// A request of a batch as the guest describes it
+#[derive(Debug)]
+pub struct Request {
+	pub url: String,
+	pub method: RequestMethod,
+	pub headers: HashMap<String, String>,
+	pub body: Vec<u8>,
+	pub options: RequestOptions,
+}
+
+// A request of a batch as it crosses the host boundary, the headers are
+// already serialized with their pseudo-headers
+#[derive(Debug)]
+pub struct BatchItem {
+	pub url: String,
+	pub method: u8,
+	pub headers: Vec<u8>,
+	pub body: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct Batch {
+	pub inner: Vec<BatchItem>,
+}
+
+// Status 0 means the body holds an error
+#[derive(Debug)]
+pub struct BatchResult {
+	pub status: u16,
+	pub headers: String,
+	pub body: Vec<u8>,
+}
+
+#[derive(Debug)]
+pub struct BatchResults {
+	pub inner: Vec<BatchResult>,
+}
+
+// Both layouts are [count, fields of every item..., data of every item...]
+fn malformed(what: &str) -> Error {
+	Error::InvalidRequest(format!("Malformed batch, {}", what))
+}
+
+fn read_i32(raw: &[u8], offset: usize) -> Result<i32, Error> {
+	let end = offset
+		.checked_add(4)
+		.ok_or_else(|| malformed("the fields overflow"))?;
+	match raw.get(offset..end) {
+		Some(b) => Ok(i32::from_le_bytes(b.try_into().unwrap())),
+		None => Err(malformed("the fields are truncated")),
+	}
+}
+
+fn read_len(raw: &[u8], offset: usize) -> Result<usize, Error> {
+	usize::try_from(read_i32(raw, offset)?).map_err(|_| malformed("a length is negative"))
+}
+
+// The count of items and the offset of their data, given the fields of an item
+fn read_count(raw: &[u8], fields: usize) -> Result<(usize, usize), Error> {
+	let count = read_len(raw, 0)?;
+	let data_offset = count
+		.checked_mul(fields)
+		.and_then(|n| n.checked_add(1))
+		.and_then(|n| n.checked_mul(4))
+		.ok_or_else(|| malformed("the count overflows"))?;
+	if data_offset > raw.len() {
+		return Err(malformed("the fields are truncated"));
+	}
+	Ok((count, data_offset))
+}
+
+// The next len bytes of data, moving the offset past them
+fn take<'a>(raw: &'a [u8], offset: &mut usize, len: usize) -> Result<&'a [u8], Error> {
+	let end = offset
+		.checked_add(len)
+		.ok_or_else(|| malformed("a length overflows"))?;
+	let data = raw
+		.get(*offset..end)
+		.ok_or_else(|| malformed("the data is truncated"))?;
+	*offset = end;
+	Ok(data)
+}
+
+fn frame(fields: Vec<Vec<i32>>, data: Vec<u8>) -> Vec<u8> {
+	let mut v = (fields.len() as i32).to_le_bytes().to_vec();
+	for f in fields.iter().flatten() {
+		v.extend(f.to_le_bytes());
+	}
+	v.extend(data);
+	v
+}
+
+impl TryFrom<&[u8]> for Batch {
+	type Error = Error;
+
+	fn try_from(raw: &[u8]) -> Result<Batch, Error> {
+		let (total_len, mut v_offset) = read_count(raw, 4)?;
+		let mut v = Vec::<BatchItem>::with_capacity(total_len);
+		for i in 0..total_len {
+			let offset = (1 + (i * 4)) * 4;
+			let url_len = read_len(raw, offset)?;
+			let url = match String::from_utf8(take(raw, &mut v_offset, url_len)?.to_vec()) {
+				Ok(u) => u,
+				Err(_) => return Err(malformed("a url is not UTF-8")),
+			};
+
+			let method = read_i32(raw, offset + 4)? as u8;
+
+			let headers_len = read_len(raw, offset + 8)?;
+			let headers = take(raw, &mut v_offset, headers_len)?.to_vec();
+
+			let body_len = read_len(raw, offset + 12)?;
+			let body = take(raw, &mut v_offset, body_len)?.to_vec();
+
+			v.push(BatchItem {
+				url,
+				method,
+				headers,
+				body,
+			});
+		}
+
+		Ok(Batch { inner: v })
+	}
+}
+
+impl Batch {
+	pub fn to_vec(&self) -> Vec<u8> {
+		let mut fields = vec![];
+		let mut data = vec![];
+		for item in self.inner.iter() {
+			fields.push(vec![
+				item.url.len() as i32,
+				item.method as i32,
+				item.headers.len() as i32,
+				item.body.len() as i32,
+			]);
+			data.extend(item.url.as_bytes());
+			data.extend(&item.headers);
+			data.extend(&item.body);
+		}
+		frame(fields, data)
+	}
+}
+
+impl TryFrom<&[u8]> for BatchResults {
+	type Error = Error;
+
+	fn try_from(raw: &[u8]) -> Result<BatchResults, Error> {
+		let (total_len, mut v_offset) = read_count(raw, 3)?;
+		let mut v = Vec::<BatchResult>::with_capacity(total_len);
+		for i in 0..total_len {
+			let offset = (1 + (i * 3)) * 4;
+			let status = read_i32(raw, offset)? as u16;
+
+			let headers_len = read_len(raw, offset + 4)?;
+			let headers = String::from_utf8(take(raw, &mut v_offset, headers_len)?.to_vec())
+				.unwrap_or_default();
+
+			let body_len = read_len(raw, offset + 8)?;
+			let body = take(raw, &mut v_offset, body_len)?.to_vec();
+
+			v.push(BatchResult {
+				status,
+				headers,
+				body,
+			});
+		}
+
+		Ok(BatchResults { inner: v })
+	}
+}
+
+impl BatchResults {
+	pub fn to_vec(&self) -> Vec<u8> {
+		let mut fields = vec![];
+		let mut data = vec![];
+		for item in self.inner.iter() {
+			fields.push(vec![
+				item.status as i32,
+				item.headers.len() as i32,
+				item.body.len() as i32,
+			]);
+			data.extend(item.headers.as_bytes());
+			data.extend(&item.body);
+		}
+		frame(fields, data)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn from_into() {
+		let batch = Batch {
+			inner: vec![
+				BatchItem {
+					url: String::from("https://a.example/x"),
+					method: 0,
+					headers: b"{}".to_vec(),
+					body: vec![],
+				},
+				BatchItem {
+					url: String::from("https://b.example/y"),
+					method: 1,
+					headers: b"{\"accept\":\"text/plain\"}".to_vec(),
+					body: b"!@#$%^&*()".to_vec(),
+				},
+			],
+		};
+
+		let batch2 = Batch::try_from(batch.to_vec().as_slice()).unwrap();
+		assert_eq!(batch.inner[1].url, batch2.inner[1].url);
+		assert_eq!(batch.inner[1].method, batch2.inner[1].method);
+		assert_eq!(batch.inner[1].headers, batch2.inner[1].headers);
+		assert_eq!(batch.inner[1].body, batch2.inner[1].body);
+
+		let results = BatchResults {
+			inner: vec![
+				BatchResult {
+					status: 200,
+					headers: String::from("{}"),
+					body: b"123".to_vec(),
+				},
+				BatchResult {
+					status: 0,
+					headers: String::from("{}"),
+					body: b"{\"code\":\"timeout\"}".to_vec(),
+				},
+			],
+		};
+
+		let results2 = BatchResults::try_from(results.to_vec().as_slice()).unwrap();
+		assert_eq!(results.inner[0].status, results2.inner[0].status);
+		assert_eq!(results.inner[1].body, results2.inner[1].body);
+	}
+
+	#[test]
+	fn malformed_input() {
+		let batch = Batch {
+			inner: vec![BatchItem {
+				url: String::from("https://a.example/x"),
+				method: 0,
+				headers: b"{}".to_vec(),
+				body: b"body".to_vec(),
+			}],
+		};
+		let raw = batch.to_vec();
+
+		let invalid = |raw: &[u8]| matches!(Batch::try_from(raw), Err(Error::InvalidRequest(_)));
+		// truncated anywhere in the fields or the data
+		for len in 0..raw.len() {
+			assert!(invalid(&raw[..len]), "truncated to {}", len);
+		}
+		assert!(Batch::try_from(raw.as_slice()).is_ok());
+
+		// counts and lengths far past the input
+		let with_i32 = |offset: usize, value: i32| {
+			let mut raw = raw.clone();
+			raw[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+			raw
+		};
+		assert!(invalid(&with_i32(0, i32::MAX)));
+		assert!(invalid(&with_i32(0, -1)));
+		assert!(invalid(&with_i32(4, i32::MAX)));
+		assert!(invalid(&with_i32(4, -1)));
+		assert!(invalid(&with_i32(16, 5)));
+		assert!(matches!(
+			BatchResults::try_from(with_i32(0, i32::MAX).as_slice()),
+			Err(Error::InvalidRequest(_))
+		));
+	}
+}
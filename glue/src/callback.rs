@@ -0,0 +1,110 @@
+use serde_json::{json, Value};
+use std::fmt;
+
+use crate::error::Error;
+use crate::headers::Headers;
+use crate::parse_result;
+
+#[link(wasm_import_module = "haiku-connector")]
+extern "C" {
+	fn get_callback_context() -> i32;
+}
+
+// Pseudo-headers naming the exported function called once an async request
+// finishes, and the data handed back to it
+pub const CALLBACK_HEADER: &str = ":callback";
+pub const CORRELATION_HEADER: &str = ":correlation";
+
+// Recorded for a callback instead of the request context and read with get().
+// The callback gets the response headers and body as its headers and body, or
+// the error as its body when the status is 0
+#[derive(Debug, Default)]
+pub struct CallbackContext {
+	pub correlation: String,
+	pub status: u16,
+}
+
+impl From<&str> for CallbackContext {
+	fn from(raw: &str) -> CallbackContext {
+		let v: Value = serde_json::from_str(raw).unwrap_or_default();
+		CallbackContext {
+			correlation: v["correlation"].as_str().unwrap_or_default().to_string(),
+			status: v["status"].as_u64().unwrap_or_default() as u16,
+		}
+	}
+}
+
+impl fmt::Display for CallbackContext {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let v = json!({
+			"correlation": self.correlation,
+			"status": self.status,
+		});
+		write!(f, "{}", v)
+	}
+}
+
+// Correlation data and status of the async request the callback is called
+// for, an error outside of a callback
+pub fn get() -> Result<CallbackContext, Error> {
+	unsafe {
+		let (_, raw) = parse_result(get_callback_context() as *mut u8)?;
+		Ok(CallbackContext::from(
+			String::from_utf8_lossy(&raw).as_ref(),
+		))
+	}
+}
+
+impl CallbackContext {
+	// Rebuild the outcome of the async request from the callback's params
+	pub fn result(&self, headers: &str, body: Vec<u8>) -> Result<(u16, Headers, Vec<u8>), Error> {
+		match self.status {
+			0 => Err(body.as_slice().into()),
+			status => Ok((status, headers.into(), body)),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn round_trip() {
+		let context = CallbackContext {
+			correlation: String::from("order-1"),
+			status: 201,
+		};
+		let parsed = CallbackContext::from(context.to_string().as_str());
+		assert_eq!(parsed.correlation, "order-1");
+		assert_eq!(parsed.status, 201);
+
+		let parsed = CallbackContext::from("not json");
+		assert_eq!((parsed.correlation.as_str(), parsed.status), ("", 0));
+	}
+
+	#[test]
+	fn result() {
+		let context = CallbackContext {
+			correlation: String::new(),
+			status: 200,
+		};
+		let (status, headers, body) = context
+			.result(r#"{"content-type":["text/plain"]}"#, b"ok".to_vec())
+			.unwrap();
+		assert_eq!(status, 200);
+		assert_eq!(headers.get("content-type").unwrap(), "text/plain");
+		assert_eq!(body, b"ok");
+
+		// a status of 0 means the body is the error of the request
+		let context = CallbackContext {
+			correlation: String::new(),
+			status: 0,
+		};
+		let e = Error::Timeout(String::from("deadline"));
+		match context.result("{}", e.to_vec()) {
+			Err(Error::Timeout(m)) => assert_eq!(m, "deadline"),
+			other => panic!("{:?}", other),
+		}
+	}
+}
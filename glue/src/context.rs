@@ -0,0 +1,88 @@
+use serde_json::{json, Value};
+use std::{collections::HashMap, fmt};
+
+use crate::error::Error;
+use crate::parse_result;
+
+#[link(wasm_import_module = "haiku-connector")]
+extern "C" {
+	fn get_request_context() -> i32;
+}
+
+// Method, route, URI, path params, peer address and id of the request being
+// handled, an error in a callback
+pub fn get() -> Result<RequestContext, Error> {
+	unsafe {
+		let (_, raw) = parse_result(get_request_context() as *mut u8)?;
+		Ok(RequestContext::from(String::from_utf8_lossy(&raw).as_ref()))
+	}
+}
+
+#[derive(Debug, Default)]
+pub struct RequestContext {
+	pub method: String,
+	pub route: String,
+	pub uri: String,
+	// params captured by the route, like `id` of `/users/:id`
+	pub paths: HashMap<String, String>,
+	pub remote_addr: String,
+	pub request_id: String,
+}
+
+impl From<&str> for RequestContext {
+	fn from(raw: &str) -> RequestContext {
+		let v: Value = serde_json::from_str(raw).unwrap_or_default();
+		let field = |name: &str| v[name].as_str().unwrap_or_default().to_string();
+		RequestContext {
+			method: field("method"),
+			route: field("route"),
+			uri: field("uri"),
+			paths: v["paths"]
+				.as_object()
+				.map(|m| {
+					m.iter()
+						.map(|(k, v)| (k.clone(), v.as_str().unwrap_or_default().to_string()))
+						.collect()
+				})
+				.unwrap_or_default(),
+			remote_addr: field("remote_addr"),
+			request_id: field("request_id"),
+		}
+	}
+}
+
+impl fmt::Display for RequestContext {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let v = json!({
+			"method": self.method,
+			"route": self.route,
+			"uri": self.uri,
+			"paths": self.paths,
+			"remote_addr": self.remote_addr,
+			"request_id": self.request_id,
+		});
+		write!(f, "{}", v)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn round_trip() {
+		let context = RequestContext {
+			method: String::from("GET"),
+			route: String::from("/users/:id"),
+			uri: String::from("/users/42?a=1"),
+			paths: HashMap::from([(String::from("id"), String::from("42"))]),
+			remote_addr: String::from("127.0.0.1:5000"),
+			request_id: String::from("r"),
+		};
+		let parsed = RequestContext::from(context.to_string().as_str());
+		assert_eq!(parsed.route, "/users/:id");
+		assert_eq!(parsed.paths["id"], "42");
+		assert_eq!(parsed.request_id, "r");
+		assert!(RequestContext::from("{}").paths.is_empty());
+	}
+}
@@ -0,0 +1,117 @@
+use serde_json::{json, Value};
+use std::fmt;
+
+// Failures of an outbound call, reported by the host as status 0 with
+// a {"code", "message"} body instead of terminating the guest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+	InvalidUrl(String),
+	InvalidRequest(String),
+	Dns(String),
+	Connect(String),
+	Timeout(String),
+	Tls(String),
+	Redirect(String),
+	Body(String),
+	TooLarge(String),
+	Forbidden(String),
+	Other(String),
+}
+
+impl Error {
+	pub fn code(&self) -> &'static str {
+		match self {
+			Error::InvalidUrl(_) => "invalid_url",
+			Error::InvalidRequest(_) => "invalid_request",
+			Error::Dns(_) => "dns",
+			Error::Connect(_) => "connect",
+			Error::Timeout(_) => "timeout",
+			Error::Tls(_) => "tls",
+			Error::Redirect(_) => "redirect",
+			Error::Body(_) => "body",
+			Error::TooLarge(_) => "too_large",
+			Error::Forbidden(_) => "forbidden",
+			Error::Other(_) => "other",
+		}
+	}
+
+	pub fn message(&self) -> &str {
+		match self {
+			Error::InvalidUrl(m)
+			| Error::InvalidRequest(m)
+			| Error::Dns(m)
+			| Error::Connect(m)
+			| Error::Timeout(m)
+			| Error::Tls(m)
+			| Error::Redirect(m)
+			| Error::Body(m)
+			| Error::TooLarge(m)
+			| Error::Forbidden(m)
+			| Error::Other(m) => m,
+		}
+	}
+
+	// Whether sending the same request again may succeed
+	pub fn is_retryable(&self) -> bool {
+		matches!(
+			self,
+			Error::Dns(_) | Error::Connect(_) | Error::Timeout(_) | Error::Body(_)
+		)
+	}
+
+	pub fn to_vec(&self) -> Vec<u8> {
+		json!({
+			"code": self.code(),
+			"message": self.message(),
+		})
+		.to_string()
+		.into_bytes()
+	}
+}
+
+impl From<&[u8]> for Error {
+	fn from(raw: &[u8]) -> Error {
+		let v: Value = serde_json::from_slice(raw).unwrap_or_default();
+		let message = v["message"].as_str().unwrap_or_default().to_string();
+		match v["code"].as_str().unwrap_or_default() {
+			"invalid_url" => Error::InvalidUrl(message),
+			"invalid_request" => Error::InvalidRequest(message),
+			"dns" => Error::Dns(message),
+			"connect" => Error::Connect(message),
+			"timeout" => Error::Timeout(message),
+			"tls" => Error::Tls(message),
+			"redirect" => Error::Redirect(message),
+			"body" => Error::Body(message),
+			"too_large" => Error::TooLarge(message),
+			"forbidden" => Error::Forbidden(message),
+			_ => Error::Other(message),
+		}
+	}
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}: {}", self.code(), self.message())
+	}
+}
+
+impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn from_into() {
+		let e = Error::Timeout(String::from("operation timed out"));
+
+		let v = e.to_vec();
+		let raw: Value = serde_json::from_slice(&v).unwrap();
+		assert_eq!(raw["code"], e.code());
+		assert_eq!(raw["message"], "operation timed out");
+
+		let e2: Error = v.as_slice().into();
+		assert_eq!(e, e2);
+		assert!(e2.is_retryable());
+	}
+}
@@ -0,0 +1,76 @@
+use std::{collections::HashMap, fmt};
+
+#[derive(Debug, Default)]
+pub struct Headers {
+	pub inner: HashMap<String, Vec<String>>,
+}
+
+impl From<&str> for Headers {
+	fn from(raw: &str) -> Headers {
+		let inner: HashMap<String, Vec<String>> = serde_json::from_str(raw).unwrap_or_default();
+		Headers {
+			inner: inner
+				.into_iter()
+				.map(|(k, v)| (k.to_ascii_lowercase(), v))
+				.collect(),
+		}
+	}
+}
+
+impl fmt::Display for Headers {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(
+			f,
+			"{}",
+			serde_json::to_string(&self.inner).unwrap_or_default()
+		)
+	}
+}
+
+impl Headers {
+	pub fn new() -> Headers {
+		Headers::default()
+	}
+
+	pub fn append(&mut self, name: &str, value: String) {
+		self.inner
+			.entry(name.to_ascii_lowercase())
+			.or_default()
+			.push(value);
+	}
+
+	pub fn get(&self, name: &str) -> Option<&String> {
+		self.get_all(name).first()
+	}
+
+	pub fn get_all(&self, name: &str) -> &[String] {
+		match self.inner.get(&name.to_ascii_lowercase()) {
+			Some(v) => v.as_slice(),
+			None => &[],
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn from_into() {
+		let mut h = Headers::new();
+		h.append("Content-Type", String::from("application/json"));
+		h.append("set-cookie", String::from("a=1"));
+		h.append("Set-Cookie", String::from("b=2"));
+
+		let s = h.to_string();
+		let raw: HashMap<String, Vec<String>> = serde_json::from_str(&s).unwrap();
+		assert_eq!(raw["set-cookie"], ["a=1", "b=2"]);
+		assert!(!raw.contains_key("Content-Type"));
+
+		let h2: Headers = s.as_str().into();
+
+		assert_eq!(h2.get("content-type").unwrap(), "application/json");
+		assert_eq!(h2.get_all("SET-COOKIE"), ["a=1", "b=2"]);
+		assert!(h2.get("x-missing").is_none());
+	}
+}
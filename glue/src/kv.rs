@@ -0,0 +1,70 @@
+use std::time::Duration;
+
+use crate::error::Error;
+use crate::parse_result;
+
+// Values are kept by the host between invocations, in a namespace of their
+// own for every connector
+#[link(wasm_import_module = "haiku-connector")]
+extern "C" {
+	fn kv_get(key_pointer: i32, key_len: i32) -> i32;
+	fn kv_set(
+		key_pointer: i32,
+		key_len: i32,
+		value_pointer: i32,
+		value_len: i32,
+		ttl_ms: i64,
+	) -> i32;
+	fn kv_delete(key_pointer: i32, key_len: i32) -> i32;
+	fn kv_list_prefix(prefix_pointer: i32, prefix_len: i32) -> i32;
+}
+
+#[inline(always)]
+fn data_params(data: &[u8]) -> (i32, i32) {
+	match data.len() {
+		0 => (0, 0),
+		len => (data.as_ptr() as i32, len as i32),
+	}
+}
+
+pub fn get(key: &str) -> Result<Option<Vec<u8>>, Error> {
+	unsafe {
+		let (key_pointer, key_len) = data_params(key.as_bytes());
+		match parse_result(kv_get(key_pointer, key_len) as *mut u8)? {
+			(404, _) => Ok(None),
+			(_, value) => Ok(Some(value)),
+		}
+	}
+}
+
+// The value expires after the TTL, or stays until it is replaced or deleted
+pub fn set(key: &str, value: &[u8], ttl: Option<Duration>) -> Result<(), Error> {
+	unsafe {
+		let (key_pointer, key_len) = data_params(key.as_bytes());
+		let (value_pointer, value_len) = data_params(value);
+		let ttl_ms = ttl.map(|t| (t.as_millis() as i64).max(1)).unwrap_or(0);
+		parse_result(kv_set(key_pointer, key_len, value_pointer, value_len, ttl_ms) as *mut u8)?;
+		Ok(())
+	}
+}
+
+// Whether the key was there
+pub fn delete(key: &str) -> Result<bool, Error> {
+	unsafe {
+		let (key_pointer, key_len) = data_params(key.as_bytes());
+		let (status, _) = parse_result(kv_delete(key_pointer, key_len) as *mut u8)?;
+		Ok(status != 404)
+	}
+}
+
+// Keys starting with the prefix, in order
+pub fn list_prefix(prefix: &str) -> Result<Vec<String>, Error> {
+	unsafe {
+		let (prefix_pointer, prefix_len) = data_params(prefix.as_bytes());
+		let (_, body) = parse_result(kv_list_prefix(prefix_pointer, prefix_len) as *mut u8)?;
+		match serde_json::from_slice(&body) {
+			Ok(keys) => Ok(keys),
+			Err(e) => Err(Error::Other(format!("Invalid key list. {}", e))),
+		}
+	}
+}
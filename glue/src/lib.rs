@@ -1,6 +1,26 @@
-use std::{collections::HashMap, fmt};
+use std::{collections::HashMap, fmt, thread, time::Duration};
 
+use error::Error;
+use options::RequestOptions;
+
+pub mod batch;
+pub mod callback;
+pub mod context;
+pub mod error;
 pub mod fileparts;
+pub mod headers;
+pub mod kv;
+pub mod options;
+pub mod secrets;
+
+// Pseudo-header carrying the name of an extension method
+pub const METHOD_HEADER: &str = ":method";
+
+const UNKNOWN_METHOD: u8 = 254;
+const EXTENSION_METHOD: u8 = 255;
+
+// Status, headers and body of one request of a batch
+pub type BatchResponse = Result<(u16, headers::Headers, Vec<u8>), Error>;
 
 #[derive(Debug)]
 pub enum RequestMethod {
@@ -8,12 +28,21 @@ pub enum RequestMethod {
 	POST,
 	PUT,
 	DELETE,
+	PATCH,
+	HEAD,
+	OPTIONS,
+	CONNECT,
+	TRACE,
+	EXTENSION(String),
 	UNKNOWN,
 }
 
 impl fmt::Display for RequestMethod {
 	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		write!(f, "{:?}", self)
+		match self {
+			RequestMethod::EXTENSION(name) => write!(f, "{}", name),
+			_ => write!(f, "{:?}", self),
+		}
 	}
 }
 
@@ -24,14 +53,57 @@ impl From<u8> for RequestMethod {
 			1 => RequestMethod::POST,
 			2 => RequestMethod::PUT,
 			3 => RequestMethod::DELETE,
+			4 => RequestMethod::PATCH,
+			5 => RequestMethod::HEAD,
+			6 => RequestMethod::OPTIONS,
+			7 => RequestMethod::CONNECT,
+			8 => RequestMethod::TRACE,
+			// the name is read from the METHOD_HEADER pseudo-header
+			EXTENSION_METHOD => RequestMethod::EXTENSION(String::new()),
 			_ => RequestMethod::UNKNOWN,
 		}
 	}
 }
 
+// Standard methods are matched in any case, extensions keep theirs
+impl From<&str> for RequestMethod {
+	fn from(o: &str) -> Self {
+		match o.to_ascii_uppercase().as_str() {
+			"GET" => RequestMethod::GET,
+			"POST" => RequestMethod::POST,
+			"PUT" => RequestMethod::PUT,
+			"DELETE" => RequestMethod::DELETE,
+			"PATCH" => RequestMethod::PATCH,
+			"HEAD" => RequestMethod::HEAD,
+			"OPTIONS" => RequestMethod::OPTIONS,
+			"CONNECT" => RequestMethod::CONNECT,
+			"TRACE" => RequestMethod::TRACE,
+			_ => RequestMethod::EXTENSION(o.to_string()),
+		}
+	}
+}
+
+impl RequestMethod {
+	pub fn code(&self) -> u8 {
+		match self {
+			RequestMethod::GET => 0,
+			RequestMethod::POST => 1,
+			RequestMethod::PUT => 2,
+			RequestMethod::DELETE => 3,
+			RequestMethod::PATCH => 4,
+			RequestMethod::HEAD => 5,
+			RequestMethod::OPTIONS => 6,
+			RequestMethod::CONNECT => 7,
+			RequestMethod::TRACE => 8,
+			RequestMethod::EXTENSION(_) => EXTENSION_METHOD,
+			RequestMethod::UNKNOWN => UNKNOWN_METHOD,
+		}
+	}
+}
+
 #[link(wasm_import_module = "haiku-connector")]
 extern "C" {
-	fn send_request(
+	fn begin_request(
 		url_pointer: i32,
 		url_len: i32,
 		method: u8,
@@ -49,7 +121,7 @@ extern "C" {
 		body_pointer: i32,
 		body_len: i32,
 	);
-	fn send_fileparts_request(
+	fn begin_fileparts_request(
 		url_pointer: i32,
 		url_len: i32,
 		method: u8,
@@ -71,6 +143,49 @@ extern "C" {
 		fileparts_pointer: i32,
 		fileparts_len: i32,
 	);
+	fn begin_batch_request(batch_pointer: i32, batch_len: i32, deadline_ms: i32) -> i32;
+	fn poll_request(handle: i32, kind: i32) -> i32;
+}
+
+// Kinds of result of poll_request
+const POLL_RESULT: i32 = 0;
+const POLL_RESULT_WITH_HEADERS: i32 = 1;
+const POLL_BATCH: i32 = 2;
+
+const MAX_POLL_PAUSE: Duration = Duration::from_millis(8);
+
+// Wait for a request begun on the host and return the pointer to its result.
+// The runtime runs one host call of the process at a time, so the guest
+// sleeps between polls rather than waiting inside one
+fn wait(handle: i32, kind: i32) -> *mut u8 {
+	let mut pause = Duration::from_millis(1);
+	loop {
+		let result_pointer = unsafe { poll_request(handle, kind) };
+		if result_pointer != 0 {
+			return result_pointer as *mut u8;
+		}
+		thread::sleep(pause);
+		pause = (pause * 2).min(MAX_POLL_PAUSE);
+	}
+}
+
+#[inline(always)]
+fn serialize_headers(
+	mut headers: HashMap<&str, String>,
+	method: &RequestMethod,
+	options: &RequestOptions,
+) -> Result<Vec<u8>, Error> {
+	if let RequestMethod::EXTENSION(name) = method {
+		headers.insert(METHOD_HEADER, name.to_string());
+	}
+	options.apply(&mut headers);
+	match serde_json::to_vec(&headers) {
+		Ok(s) => Ok(s),
+		Err(e) => Err(Error::InvalidRequest(format!(
+			"Failed to parse headers. {}",
+			e
+		))),
+	}
 }
 
 #[inline(always)]
@@ -79,7 +194,7 @@ fn parse_params(
 	method: RequestMethod,
 	headers: &mut Vec<u8>,
 	body: &mut [u8],
-) -> Result<(i32, i32, u8, i32, i32, i32, i32), String> {
+) -> Result<(i32, i32, u8, i32, i32, i32, i32), Error> {
 	unsafe {
 		let url = url.as_bytes_mut();
 		let url_pointer = url.as_mut_ptr() as i32;
@@ -96,7 +211,7 @@ fn parse_params(
 		Ok((
 			url_pointer,
 			url_len,
-			method as u8,
+			method.code(),
 			headers_pointer,
 			headers_len,
 			body_pointer,
@@ -112,12 +227,10 @@ fn parse_fileparts_params(
 	headers: &mut Vec<u8>,
 	body: &mut [u8],
 	fileparts: &mut Vec<u8>,
-) -> Result<(i32, i32, u8, i32, i32, i32, i32, i32, i32), String> {
+) -> Result<(i32, i32, u8, i32, i32, i32, i32, i32, i32), Error> {
 	let (fileparts_pointer, fileparts_len) = match fileparts.len() {
 		0 => (0, 0),
-		_ => {
-			(fileparts.as_mut_ptr() as i32, fileparts.len() as i32)
-		}
+		_ => (fileparts.as_mut_ptr() as i32, fileparts.len() as i32),
 	};
 
 	match parse_params(url, method, headers, body) {
@@ -144,26 +257,60 @@ fn parse_fileparts_params(
 	}
 }
 
+// Read the [body pointer, body len, status] result of the host functions,
+// status 0 means the body holds an error instead of a response
+#[inline(always)]
+unsafe fn parse_result(result_pointer: *mut u8) -> Result<(u16, Vec<u8>), Error> {
+	let whole = Vec::from_raw_parts(result_pointer, 12, 12);
+	let status = i32::from_le_bytes((&whole[8..]).try_into().unwrap());
+	let ret_len = i32::from_le_bytes((&whole[4..8]).try_into().unwrap());
+	let ret_pointer = i32::from_le_bytes((&whole[..4]).try_into().unwrap());
+	let ret = Vec::from_raw_parts(ret_pointer as *mut u8, ret_len as usize, ret_len as usize);
+
+	match status {
+		0 => Err(ret.as_slice().into()),
+		_ => Ok((status as u16, ret)),
+	}
+}
+
+// Read the [body pointer, body len, status, headers pointer, headers len]
+// result of the *_with_headers host functions
+#[inline(always)]
+unsafe fn parse_result_with_headers(
+	result_pointer: *mut u8,
+) -> Result<(u16, headers::Headers, Vec<u8>), Error> {
+	let whole = Vec::from_raw_parts(result_pointer, 20, 20);
+	let ret_pointer = i32::from_le_bytes((&whole[..4]).try_into().unwrap());
+	let ret_len = i32::from_le_bytes((&whole[4..8]).try_into().unwrap());
+	let status = i32::from_le_bytes((&whole[8..12]).try_into().unwrap());
+	let headers_pointer = i32::from_le_bytes((&whole[12..16]).try_into().unwrap());
+	let headers_len = i32::from_le_bytes((&whole[16..]).try_into().unwrap());
+
+	let ret = Vec::from_raw_parts(ret_pointer as *mut u8, ret_len as usize, ret_len as usize);
+	let ret_headers = String::from_raw_parts(
+		headers_pointer as *mut u8,
+		headers_len as usize,
+		headers_len as usize,
+	);
+
+	match status {
+		0 => Err(ret.as_slice().into()),
+		_ => Ok((status as u16, ret_headers.as_str().into(), ret)),
+	}
+}
+
 pub fn request(
 	mut url: String,
 	method: RequestMethod,
 	headers: HashMap<&str, String>,
 	mut body: Vec<u8>,
-) -> Result<(u16, Vec<u8>), String> {
+) -> Result<(u16, Vec<u8>), Error> {
 	unsafe {
-		let mut headers = match serde_json::to_vec(&headers) {
-			Ok(s) => s,
-			Err(_) => {
-				return Err(String::from("Failed to parse headers"));
-			}
-		};
+		let mut headers = serialize_headers(headers, &method, &RequestOptions::default())?;
 
 		let (url_pointer, url_len, method, headers_pointer, headers_len, body_pointer, body_len) =
-			match parse_params(url.as_mut(), method, &mut headers, &mut body) {
-				Ok(p) => p,
-				Err(e) => return Err(e),
-			};
-		let result_pointer = send_request(
+			parse_params(url.as_mut(), method, &mut headers, &mut body)?;
+		let handle = begin_request(
 			url_pointer,
 			url_len,
 			method,
@@ -171,15 +318,46 @@ pub fn request(
 			headers_len,
 			body_pointer,
 			body_len,
-		) as *mut u8;
+		);
+		let result_pointer = wait(handle, POLL_RESULT);
+
+		parse_result(result_pointer)
+	}
+}
 
-		let whole = Vec::from_raw_parts(result_pointer, 12, 12);
-		let status = i32::from_le_bytes((&whole[8..]).try_into().unwrap());
-		let ret_len = i32::from_le_bytes((&whole[4..8]).try_into().unwrap());
-		let ret_pointer = i32::from_le_bytes((&whole[..4]).try_into().unwrap());
-		let ret = Vec::from_raw_parts(ret_pointer as *mut u8, ret_len as usize, ret_len as usize);
+pub fn request_with_headers(
+	url: String,
+	method: RequestMethod,
+	headers: HashMap<&str, String>,
+	body: Vec<u8>,
+) -> Result<(u16, headers::Headers, Vec<u8>), Error> {
+	request_with_options(url, method, headers, body, RequestOptions::default())
+}
 
-		Ok((status as u16, ret))
+pub fn request_with_options(
+	mut url: String,
+	method: RequestMethod,
+	headers: HashMap<&str, String>,
+	mut body: Vec<u8>,
+	options: RequestOptions,
+) -> Result<(u16, headers::Headers, Vec<u8>), Error> {
+	unsafe {
+		let mut headers = serialize_headers(headers, &method, &options)?;
+
+		let (url_pointer, url_len, method, headers_pointer, headers_len, body_pointer, body_len) =
+			parse_params(url.as_mut(), method, &mut headers, &mut body)?;
+		let handle = begin_request(
+			url_pointer,
+			url_len,
+			method,
+			headers_pointer,
+			headers_len,
+			body_pointer,
+			body_len,
+		);
+		let result_pointer = wait(handle, POLL_RESULT_WITH_HEADERS);
+
+		parse_result_with_headers(result_pointer)
 	}
 }
 
@@ -188,20 +366,12 @@ pub fn async_request(
 	method: RequestMethod,
 	headers: HashMap<&str, String>,
 	mut body: Vec<u8>,
-) -> Result<(), String> {
+) -> Result<(), Error> {
 	unsafe {
-		let mut headers = match serde_json::to_vec(&headers) {
-			Ok(s) => s,
-			Err(_) => {
-				return Err(String::from("Failed to parse headers"));
-			}
-		};
+		let mut headers = serialize_headers(headers, &method, &RequestOptions::default())?;
 
 		let (url_pointer, url_len, method, headers_pointer, headers_len, body_pointer, body_len) =
-			match parse_params(url.as_mut(), method, &mut headers, &mut body) {
-				Ok(p) => p,
-				Err(e) => return Err(e),
-			};
+			parse_params(url.as_mut(), method, &mut headers, &mut body)?;
 
 		send_async_request(
 			url_pointer,
@@ -217,20 +387,30 @@ pub fn async_request(
 	}
 }
 
+// Like async_request, then call the exported `callback` with the correlation
+// data and the outcome once the request finishes, see callback::CallbackContext
+pub fn async_request_with_callback(
+	url: String,
+	method: RequestMethod,
+	mut headers: HashMap<&str, String>,
+	body: Vec<u8>,
+	callback: &str,
+	correlation: String,
+) -> Result<(), Error> {
+	headers.insert(callback::CALLBACK_HEADER, callback.to_string());
+	headers.insert(callback::CORRELATION_HEADER, correlation);
+	async_request(url, method, headers, body)
+}
+
 pub fn fileparts_request(
 	mut url: String,
 	method: RequestMethod,
 	headers: HashMap<&str, String>,
 	mut body: Vec<u8>,
 	fileparts: fileparts::FileParts,
-) -> Result<(u16, Vec<u8>), String> {
+) -> Result<(u16, Vec<u8>), Error> {
 	unsafe {
-		let mut headers = match serde_json::to_vec(&headers) {
-			Ok(s) => s,
-			Err(_) => {
-				return Err(String::from("Failed to parse headers"));
-			}
-		};
+		let mut headers = serialize_headers(headers, &method, &RequestOptions::default())?;
 
 		let mut fileparts = fileparts.to_vec();
 
@@ -244,17 +424,14 @@ pub fn fileparts_request(
 			body_len,
 			fileparts_pointer,
 			fileparts_len,
-		) = match parse_fileparts_params(
+		) = parse_fileparts_params(
 			url.as_mut(),
 			method,
 			&mut headers,
 			&mut body,
 			&mut fileparts,
-		) {
-			Ok(p) => p,
-			Err(e) => return Err(e),
-		};
-		let result_pointer = send_fileparts_request(
+		)?;
+		let handle = begin_fileparts_request(
 			url_pointer,
 			url_len,
 			method,
@@ -264,15 +441,74 @@ pub fn fileparts_request(
 			body_len,
 			fileparts_pointer,
 			fileparts_len,
-		) as *mut u8;
+		);
+		let result_pointer = wait(handle, POLL_RESULT);
 
-		let whole = Vec::from_raw_parts(result_pointer, 12, 12);
-		let status = i32::from_le_bytes((&whole[8..]).try_into().unwrap());
-		let ret_len = i32::from_le_bytes((&whole[4..8]).try_into().unwrap());
-		let ret_pointer = i32::from_le_bytes((&whole[..4]).try_into().unwrap());
-		let ret = Vec::from_raw_parts(ret_pointer as *mut u8, ret_len as usize, ret_len as usize);
+		parse_result(result_pointer)
+	}
+}
 
-		Ok((status as u16, ret))
+pub fn fileparts_request_with_headers(
+	url: String,
+	method: RequestMethod,
+	headers: HashMap<&str, String>,
+	body: Vec<u8>,
+	fileparts: fileparts::FileParts,
+) -> Result<(u16, headers::Headers, Vec<u8>), Error> {
+	fileparts_request_with_options(
+		url,
+		method,
+		headers,
+		body,
+		fileparts,
+		RequestOptions::default(),
+	)
+}
+
+pub fn fileparts_request_with_options(
+	mut url: String,
+	method: RequestMethod,
+	headers: HashMap<&str, String>,
+	mut body: Vec<u8>,
+	fileparts: fileparts::FileParts,
+	options: RequestOptions,
+) -> Result<(u16, headers::Headers, Vec<u8>), Error> {
+	unsafe {
+		let mut headers = serialize_headers(headers, &method, &options)?;
+
+		let mut fileparts = fileparts.to_vec();
+
+		let (
+			url_pointer,
+			url_len,
+			method,
+			headers_pointer,
+			headers_len,
+			body_pointer,
+			body_len,
+			fileparts_pointer,
+			fileparts_len,
+		) = parse_fileparts_params(
+			url.as_mut(),
+			method,
+			&mut headers,
+			&mut body,
+			&mut fileparts,
+		)?;
+		let handle = begin_fileparts_request(
+			url_pointer,
+			url_len,
+			method,
+			headers_pointer,
+			headers_len,
+			body_pointer,
+			body_len,
+			fileparts_pointer,
+			fileparts_len,
+		);
+		let result_pointer = wait(handle, POLL_RESULT_WITH_HEADERS);
+
+		parse_result_with_headers(result_pointer)
 	}
 }
 
@@ -282,14 +518,9 @@ pub fn async_fileparts_request(
 	headers: HashMap<&str, String>,
 	mut body: Vec<u8>,
 	fileparts: fileparts::FileParts,
-) -> Result<(), String> {
+) -> Result<(), Error> {
 	unsafe {
-		let mut headers = match serde_json::to_vec(&headers) {
-			Ok(s) => s,
-			Err(_) => {
-				return Err(String::from("Failed to parse headers"));
-			}
-		};
+		let mut headers = serialize_headers(headers, &method, &RequestOptions::default())?;
 
 		let mut fileparts = fileparts.to_vec();
 
@@ -303,16 +534,13 @@ pub fn async_fileparts_request(
 			body_len,
 			fileparts_pointer,
 			fileparts_len,
-		) = match parse_fileparts_params(
+		) = parse_fileparts_params(
 			url.as_mut(),
 			method,
 			&mut headers,
 			&mut body,
 			&mut fileparts,
-		) {
-			Ok(p) => p,
-			Err(e) => return Err(e),
-		};
+		)?;
 
 		send_async_fileparts_request(
 			url_pointer,
@@ -329,3 +557,161 @@ pub fn async_fileparts_request(
 		Ok(())
 	}
 }
+
+// Like async_fileparts_request, then call the exported `callback` with the
+// correlation data and the outcome once the request finishes
+pub fn async_fileparts_request_with_callback(
+	url: String,
+	method: RequestMethod,
+	mut headers: HashMap<&str, String>,
+	body: Vec<u8>,
+	fileparts: fileparts::FileParts,
+	callback: &str,
+	correlation: String,
+) -> Result<(), Error> {
+	headers.insert(callback::CALLBACK_HEADER, callback.to_string());
+	headers.insert(callback::CORRELATION_HEADER, correlation);
+	async_fileparts_request(url, method, headers, body, fileparts)
+}
+
+// Send the requests concurrently and return their results in the same order
+// once all of them completed. Every request keeps its own timeout option,
+// those still running at the deadline fail with Error::Timeout
+pub fn batch_request(
+	requests: Vec<batch::Request>,
+	deadline_ms: Option<u64>,
+) -> Vec<BatchResponse> {
+	let items: Vec<Result<batch::BatchItem, Error>> = requests
+		.into_iter()
+		.map(|r| {
+			let headers = r
+				.headers
+				.iter()
+				.map(|(k, v)| (k.as_str(), v.clone()))
+				.collect();
+			Ok(batch::BatchItem {
+				headers: serialize_headers(headers, &r.method, &r.options)?,
+				url: r.url,
+				method: r.method.code(),
+				body: r.body,
+			})
+		})
+		.collect();
+
+	let mut sent = vec![];
+	let mut results = vec![];
+	for item in items.into_iter() {
+		match item {
+			Ok(item) => {
+				sent.push(item);
+				results.push(None);
+			}
+			Err(e) => results.push(Some(Err(e))),
+		}
+	}
+
+	let sent_len = sent.len();
+	let ret = if sent.is_empty() {
+		vec![]
+	} else {
+		unsafe {
+			let mut batch = batch::Batch { inner: sent }.to_vec();
+			let deadline_ms = deadline_ms.unwrap_or(0).min(i32::MAX as u64) as i32;
+			let handle =
+				begin_batch_request(batch.as_mut_ptr() as i32, batch.len() as i32, deadline_ms);
+			let result_pointer = wait(handle, POLL_BATCH);
+
+			let whole = Vec::from_raw_parts(result_pointer, 8, 8);
+			let ret_pointer = i32::from_le_bytes((&whole[..4]).try_into().unwrap());
+			let ret_len = i32::from_le_bytes((&whole[4..]).try_into().unwrap());
+			let ret =
+				Vec::from_raw_parts(ret_pointer as *mut u8, ret_len as usize, ret_len as usize);
+			match batch::BatchResults::try_from(ret.as_slice()) {
+				Ok(results) => results.inner,
+				Err(e) => vec![batch::BatchResult {
+					status: 0,
+					headers: String::from("{}"),
+					body: e.to_vec(),
+				}],
+			}
+		}
+	};
+	// a batch the host couldn't read gets a single error, shared by every request
+	let mut ret = match ret.as_slice() {
+		[r] if r.status == 0 && sent_len > 1 => (0..sent_len)
+			.map(|_| batch::BatchResult {
+				status: 0,
+				headers: r.headers.clone(),
+				body: r.body.clone(),
+			})
+			.collect(),
+		_ => ret,
+	}
+	.into_iter();
+
+	results
+		.into_iter()
+		.map(|r| match r {
+			Some(e) => e,
+			None => match ret.next() {
+				Some(r) if r.status == 0 => Err(r.body.as_slice().into()),
+				Some(r) => Ok((r.status, r.headers.as_str().into(), r.body)),
+				None => Err(Error::Other(String::from("Missing batch result"))),
+			},
+		})
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const STANDARD: [&str; 9] = [
+		"GET", "POST", "PUT", "DELETE", "PATCH", "HEAD", "OPTIONS", "CONNECT", "TRACE",
+	];
+
+	#[test]
+	fn method_names() {
+		for name in STANDARD {
+			assert_eq!(RequestMethod::from(name).to_string(), name);
+			assert_eq!(
+				RequestMethod::from(name.to_lowercase().as_str()).to_string(),
+				name
+			);
+		}
+		let method = RequestMethod::from("Purge");
+		assert_eq!(method.code(), EXTENSION_METHOD);
+		assert_eq!(method.to_string(), "Purge");
+	}
+
+	#[test]
+	fn method_codes() {
+		for (code, name) in STANDARD.iter().enumerate() {
+			let method = RequestMethod::from(code as u8);
+			assert_eq!(method.to_string(), *name);
+			assert_eq!(method.code(), code as u8);
+		}
+		assert_eq!(
+			RequestMethod::from(EXTENSION_METHOD).code(),
+			EXTENSION_METHOD
+		);
+		assert_eq!(RequestMethod::from(9).code(), UNKNOWN_METHOD);
+		assert_eq!(RequestMethod::UNKNOWN.code(), UNKNOWN_METHOD);
+	}
+
+	#[test]
+	fn method_header() {
+		let options = RequestOptions::default();
+		let headers = HashMap::from([("Accept", String::from("*/*"))]);
+
+		let method = RequestMethod::from("PURGE");
+		let raw = serialize_headers(headers.clone(), &method, &options).unwrap();
+		let sent: HashMap<String, String> = serde_json::from_slice(&raw).unwrap();
+		assert_eq!(sent[METHOD_HEADER], "PURGE");
+		assert_eq!(sent["Accept"], "*/*");
+
+		let raw = serialize_headers(headers, &RequestMethod::GET, &options).unwrap();
+		let sent: HashMap<String, String> = serde_json::from_slice(&raw).unwrap();
+		assert!(!sent.contains_key(METHOD_HEADER));
+	}
+}
@@ -0,0 +1,74 @@
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+// Pseudo-headers carrying the options, they are never sent upstream
+pub const TIMEOUT_HEADER: &str = ":timeout-ms";
+pub const REDIRECT_HEADER: &str = ":max-redirects";
+pub const GZIP_HEADER: &str = ":gzip";
+pub const MAX_RESPONSE_SIZE_HEADER: &str = ":max-response-size";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RequestOptions {
+	// Overrides the host's default timeout
+	pub timeout_ms: Option<u64>,
+	// Redirects to follow, 0 returns the redirect response itself
+	pub max_redirects: Option<usize>,
+	// Ask for a compressed response and decompress it
+	pub gzip: bool,
+	// Fail with Error::TooLarge instead of reading a bigger body
+	pub max_response_size: Option<usize>,
+}
+
+impl From<&Map<String, Value>> for RequestOptions {
+	fn from(headers: &Map<String, Value>) -> RequestOptions {
+		let field = |name: &str| headers.get(name).and_then(|v| v.as_str());
+		RequestOptions {
+			timeout_ms: field(TIMEOUT_HEADER).and_then(|v| v.parse().ok()),
+			max_redirects: field(REDIRECT_HEADER).and_then(|v| v.parse().ok()),
+			gzip: field(GZIP_HEADER) == Some("true"),
+			max_response_size: field(MAX_RESPONSE_SIZE_HEADER).and_then(|v| v.parse().ok()),
+		}
+	}
+}
+
+impl RequestOptions {
+	pub fn apply(&self, headers: &mut HashMap<&str, String>) {
+		if let Some(timeout_ms) = self.timeout_ms {
+			headers.insert(TIMEOUT_HEADER, timeout_ms.to_string());
+		}
+		if let Some(max_redirects) = self.max_redirects {
+			headers.insert(REDIRECT_HEADER, max_redirects.to_string());
+		}
+		if self.gzip {
+			headers.insert(GZIP_HEADER, String::from("true"));
+		}
+		if let Some(max_response_size) = self.max_response_size {
+			headers.insert(MAX_RESPONSE_SIZE_HEADER, max_response_size.to_string());
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn from_into() {
+		let options = RequestOptions {
+			timeout_ms: Some(1500),
+			max_redirects: Some(0),
+			gzip: true,
+			max_response_size: None,
+		};
+
+		let mut headers = HashMap::new();
+		headers.insert("accept", String::from("application/json"));
+		options.apply(&mut headers);
+
+		let raw = serde_json::to_string(&headers).unwrap();
+		let v: Value = serde_json::from_str(&raw).unwrap();
+		let options2: RequestOptions = v.as_object().unwrap().into();
+
+		assert_eq!(options, options2);
+	}
+}
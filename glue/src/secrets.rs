@@ -0,0 +1,25 @@
+use crate::error::Error;
+use crate::parse_result;
+
+#[link(wasm_import_module = "haiku-connector")]
+extern "C" {
+	fn get_secret(name_pointer: i32, name_len: i32) -> i32;
+}
+
+// A secret declared in the connector's config, None when it isn't declared.
+// Plain environment variables are read with std::env instead
+pub fn get(name: &str) -> Result<Option<String>, Error> {
+	unsafe {
+		let (name_pointer, name_len) = match name.len() {
+			0 => (0, 0),
+			len => (name.as_ptr() as i32, len as i32),
+		};
+		match parse_result(get_secret(name_pointer, name_len) as *mut u8)? {
+			(404, _) => Ok(None),
+			(_, value) => match String::from_utf8(value) {
+				Ok(v) => Ok(Some(v)),
+				Err(e) => Err(Error::Other(format!("Invalid secret. {}", e))),
+			},
+		}
+	}
+}